@@ -0,0 +1,104 @@
+//! Throughput of bulk transfers between a client and a server on loopback
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::any::Any;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use usbip::*;
+
+/// Answers bulk IN with as many bytes as the last bulk OUT
+#[derive(Default)]
+struct BulkHandler {
+    length: usize,
+}
+
+impl UsbInterfaceHandler for BulkHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        match ep.direction() {
+            Direction::In => Ok(vec![0x55; self.length]),
+            Direction::Out => {
+                self.length = req.len();
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn bulk_server() -> UsbIpServer {
+    let handler = Arc::new(Mutex::new(
+        Box::new(BulkHandler::default()) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+        0xff,
+        0x00,
+        0x00,
+        "Bulk",
+        vec![
+            UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+            UsbEndpoint {
+                address: 0x01,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            },
+        ],
+        handler,
+    )])
+}
+
+fn loopback(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (handle, device) = runtime.block_on(async {
+        let handle = bulk_server()
+            .serve((Ipv4Addr::LOCALHOST, 0).into())
+            .await
+            .unwrap();
+        let device = UsbIpClient::connect(handle.local_addr())
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+        (handle, device)
+    });
+
+    // bulk IN after bulk OUT of the same size
+    let mut group = c.benchmark_group("loopback");
+    for size in [512usize, 16 * 1024, 256 * 1024] {
+        group.throughput(Throughput::Bytes(size as u64));
+        let data = vec![0xaa; size];
+        group.bench_with_input(BenchmarkId::new("bulk_out", size), &data, |b, data| {
+            b.to_async(&runtime)
+                .iter(|| async { device.bulk_out(0x01, data).await.unwrap() })
+        });
+        group.bench_with_input(BenchmarkId::new("bulk_in", size), &size, |b, &size| {
+            b.to_async(&runtime)
+                .iter(|| async { device.bulk_in(0x81, size as u32).await.unwrap() })
+        });
+    }
+    group.finish();
+
+    runtime.block_on(handle.shutdown());
+}
+
+criterion_group!(benches, loopback);
+criterion_main!(benches);
@@ -1,7 +1,5 @@
-use env_logger;
 use std::net::*;
 use std::time::Duration;
-use usbip;
 
 #[tokio::main]
 async fn main() {
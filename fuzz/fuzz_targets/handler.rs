@@ -0,0 +1,52 @@
+//! Feed arbitrary bytes from a client to a server with simulated devices
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::sync::{Arc, Mutex};
+use usbip::*;
+
+fn simulated_server() -> UsbIpServer {
+    let hid = Arc::new(Mutex::new(
+        Box::new(hid::UsbHidKeyboardHandler::new_keyboard()) as Box<dyn UsbInterfaceHandler + Send>,
+    ));
+    let cdc = Arc::new(Mutex::new(
+        Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+    ));
+    UsbIpServer::new_simulated(vec![
+        UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Fuzz HID",
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 0x08,
+                interval: 10,
+            }],
+            hid,
+        ),
+        UsbDevice::new(1).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Fuzz CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            cdc,
+        ),
+    ])
+}
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let server = Arc::new(simulated_server());
+        // errors are fine, panics are not
+        serve_connection(tokio::io::join(data, tokio::io::sink()), server)
+            .await
+            .ok();
+    });
+});
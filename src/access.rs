@@ -0,0 +1,203 @@
+//! Access control of clients to devices
+use super::*;
+use std::net::IpAddr;
+
+/// Decides which devices a client may list and import, see [UsbIpServer::with_access_policy]
+///
+/// Implemented for closures deciding both at once
+pub trait AccessPolicy: Send + Sync {
+    /// Whether `device` appears in OP_REP_DEVLIST to the client
+    ///
+    /// Devices not listed cannot be imported either, they appear not to exist
+    fn can_list(&self, info: &ConnectionInfo, device: &UsbDevice) -> bool;
+
+    /// Whether the client may import `device`, otherwise the import fails with [ST_NA]
+    fn can_import(&self, info: &ConnectionInfo, device: &UsbDevice) -> bool {
+        self.can_list(info, device)
+    }
+}
+
+impl<F> AccessPolicy for F
+where
+    F: Fn(&ConnectionInfo, &UsbDevice) -> bool + Send + Sync,
+{
+    fn can_list(&self, info: &ConnectionInfo, device: &UsbDevice) -> bool {
+        self(info, device)
+    }
+}
+
+/// Allow clients from a list of IP networks to access all devices
+///
+/// Clients without an IP address, e.g. on Unix sockets, are denied
+#[derive(Clone, Debug, Default)]
+pub struct IpAllowlist {
+    /// Network addresses with their prefix length
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl IpAllowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow clients in `network`, an address like `10.0.0.1` or a CIDR like `192.168.1.0/24` and `fd00::/8`
+    pub fn allow(mut self, network: &str) -> Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid network {:?}", network),
+            )
+        };
+        let (addr, prefix_len) = match network.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        self.networks.push((addr, prefix_len));
+        Ok(self)
+    }
+
+    /// Whether `addr` is in an allowed network
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener appear as IPv4-mapped IPv6 addresses
+        let addr = addr.to_canonical();
+        self.networks
+            .iter()
+            .any(|&(network, prefix_len)| match (network, addr) {
+                (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                    prefix_matches(u32::from(network), u32::from(addr), prefix_len, 32)
+                }
+                (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                    prefix_matches(u128::from(network), u128::from(addr), prefix_len, 128)
+                }
+                _ => false,
+            })
+    }
+}
+
+fn prefix_matches<T>(network: T, addr: T, prefix_len: u8, bits: u8) -> bool
+where
+    T: std::ops::BitXor<Output = T> + std::ops::Shr<u32, Output = T> + PartialEq + Default,
+{
+    if prefix_len == 0 {
+        return true;
+    }
+    // compare the top prefix_len bits
+    (network ^ addr) >> (bits - prefix_len) as u32 == T::default()
+}
+
+impl AccessPolicy for IpAllowlist {
+    fn can_list(&self, info: &ConnectionInfo, _device: &UsbDevice) -> bool {
+        info.peer_addr.is_some_and(|addr| self.contains(addr.ip()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[test]
+    fn allowlist_contains() {
+        let allowlist = IpAllowlist::new()
+            .allow("192.168.1.0/24")
+            .unwrap()
+            .allow("10.0.0.1")
+            .unwrap()
+            .allow("fd00::/8")
+            .unwrap();
+        assert!(allowlist.contains("192.168.1.42".parse().unwrap()));
+        assert!(!allowlist.contains("192.168.2.1".parse().unwrap()));
+        assert!(allowlist.contains("10.0.0.1".parse().unwrap()));
+        assert!(!allowlist.contains("10.0.0.2".parse().unwrap()));
+        assert!(allowlist.contains("fd12::1".parse().unwrap()));
+        assert!(!allowlist.contains("fe80::1".parse().unwrap()));
+        assert!(allowlist.contains("::ffff:192.168.1.1".parse().unwrap()));
+
+        assert!(IpAllowlist::new()
+            .allow("0.0.0.0/0")
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert!(IpAllowlist::new().allow("10.0.0.0/33").is_err());
+        assert!(IpAllowlist::new().allow("localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn allowlist_denies_import() {
+        let server = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+                .with_access_policy(IpAllowlist::new().allow("127.0.0.0/8").unwrap()),
+        );
+        let denied = ConnectionInfo {
+            peer_addr: Some("192.168.1.1:1234".parse().unwrap()),
+            ..ConnectionInfo::default()
+        };
+        let allowed = ConnectionInfo {
+            peer_addr: Some("127.0.0.1:1234".parse().unwrap()),
+            ..ConnectionInfo::default()
+        };
+
+        // OP_REQ_DEVLIST
+        let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        handler(&mut mock_socket, server.clone(), denied.clone())
+            .await
+            .ok();
+        // OP_REP_DEVLIST without devices
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        let req = import_request("0").await;
+        let mut mock_socket = MockSocket::new(req.clone());
+        handler(&mut mock_socket, server.clone(), denied).await.ok();
+        // OP_REP_IMPORT with ST_NODEV
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04]
+        );
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone(), allowed)
+            .await
+            .ok();
+        // OP_REP_IMPORT
+        assert_eq!(mock_socket.output.len(), 0x140);
+    }
+
+    #[tokio::test]
+    async fn policy_denies_import_of_listed_device() {
+        struct ListOnly;
+        impl AccessPolicy for ListOnly {
+            fn can_list(&self, _info: &ConnectionInfo, _device: &UsbDevice) -> bool {
+                true
+            }
+
+            fn can_import(&self, _info: &ConnectionInfo, _device: &UsbDevice) -> bool {
+                false
+            }
+        }
+        let server = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_access_policy(ListOnly),
+        );
+
+        let req = import_request("0").await;
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server, ConnectionInfo::default())
+            .await
+            .ok();
+        // OP_REP_IMPORT with ST_NA
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]
+        );
+    }
+}
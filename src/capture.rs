@@ -0,0 +1,516 @@
+//! Capture URB traffic to pcap files in the Linux usbmon format
+use super::*;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// LINKTYPE_USB_LINUX_MMAPPED
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+/// Size of the usbmon header of each packet
+const USBMON_HEADER_SIZE: usize = 64;
+/// Size of an isochronous packet descriptor following the usbmon header
+const USBMON_ISO_DESC_SIZE: usize = 16;
+/// Size of the pcap file header
+const PCAP_HEADER_SIZE: u64 = 24;
+/// Maximum length of a captured packet, longer ones are truncated
+const PCAP_SNAPLEN: u32 = 0x40000;
+
+/// Where and how to capture the URBs of a device, see [UsbDevice::with_capture]
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    path: PathBuf,
+    max_file_size: Option<u64>,
+    max_files: usize,
+}
+
+impl CaptureConfig {
+    /// Capture to a pcap file at `path`, overwriting it
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_file_size: None,
+            max_files: 1,
+        }
+    }
+
+    /// Start a new file once the current one would exceed `bytes`
+    ///
+    /// Old files are renamed to `<path>.1`, `<path>.2` and so on, see [CaptureConfig::with_max_files].
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Keep at most `count` files including the current one, default to 1
+    pub fn with_max_files(mut self, count: usize) -> Self {
+        self.max_files = count.max(1);
+        self
+    }
+}
+
+/// An event of a URB, as in usbmon
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EventType {
+    Submit = b'S' as isize,
+    Complete = b'C' as isize,
+}
+
+/// A URB event to write
+struct Event<'a> {
+    kind: EventType,
+    header: UsbIpHeaderBasic,
+    /// Requested length on submission, actual length on completion
+    length: u32,
+    status: i32,
+    setup: Option<[u8; 8]>,
+    transfer_flags: u32,
+    start_frame: u32,
+    interval: u32,
+    error_count: u32,
+    iso_packets: &'a [IsoPacketDescriptor],
+    data: &'a [u8],
+}
+
+struct CaptureFile {
+    file: File,
+    size: u64,
+}
+
+/// Sent to the writer thread of a [Capture]
+enum CaptureMessage {
+    /// A pcap record, header included
+    Record(Vec<u8>),
+    /// Acknowledged once all records before are written
+    #[cfg(test)]
+    Flush(std::sync::mpsc::SyncSender<()>),
+}
+
+/// Writes the URBs of one device to pcap files
+///
+/// Records are written by a thread of its own, so that file IO and rotation do not block the connection.
+/// The thread stops once the capture is dropped and the queued records are written.
+pub(crate) struct Capture {
+    records: std::sync::mpsc::Sender<CaptureMessage>,
+}
+
+impl Capture {
+    pub(crate) fn new(config: CaptureConfig) -> Self {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut writer = CaptureWriter { config, file: None };
+            for message in rx {
+                match message {
+                    CaptureMessage::Record(record) => {
+                        if let Err(err) = writer.write_record(&record) {
+                            warn!("Failed to capture to {:?}: {}", writer.config.path, err);
+                        }
+                    }
+                    #[cfg(test)]
+                    CaptureMessage::Flush(done) => {
+                        done.send(()).ok();
+                    }
+                }
+            }
+        });
+        Self { records: tx }
+    }
+
+    /// Record a USBIP_CMD_SUBMIT
+    pub(crate) fn submit(&self, device: &UsbDevice, cmd: &CmdSubmit) {
+        self.write(
+            device,
+            Event {
+                kind: EventType::Submit,
+                header: cmd.header,
+                length: cmd.transfer_buffer_length,
+                status: -EINPROGRESS,
+                setup: Some(cmd.setup),
+                transfer_flags: cmd.transfer_flags,
+                start_frame: cmd.start_frame,
+                interval: cmd.interval,
+                error_count: 0,
+                iso_packets: &cmd.iso_packet_descriptor,
+                data: &cmd.transfer_buffer,
+            },
+        );
+    }
+
+    /// Record a USBIP_RET_SUBMIT
+    pub(crate) fn complete(&self, device: &UsbDevice, ret: &RetSubmit) {
+        self.write(
+            device,
+            Event {
+                kind: EventType::Complete,
+                header: ret.header,
+                length: ret.actual_length,
+                status: ret.status,
+                setup: None,
+                transfer_flags: 0,
+                start_frame: ret.start_frame,
+                interval: 0,
+                error_count: ret.error_count,
+                iso_packets: &ret.iso_packet_descriptor,
+                data: &ret.transfer_buffer,
+            },
+        );
+    }
+
+    /// Record the completion of a URB without data, cancelled by USBIP_CMD_UNLINK or rejected on
+    /// submission
+    pub(crate) fn failed(&self, device: &UsbDevice, header: UsbIpHeaderBasic, status: i32) {
+        self.write(
+            device,
+            Event {
+                kind: EventType::Complete,
+                header,
+                length: 0,
+                status,
+                setup: None,
+                transfer_flags: 0,
+                start_frame: 0,
+                interval: 0,
+                error_count: 0,
+                iso_packets: &[],
+                data: &[],
+            },
+        );
+    }
+
+    /// Wait until all events recorded so far are written
+    #[cfg(test)]
+    pub(crate) fn flush(&self) {
+        let (done, wait) = std::sync::mpsc::sync_channel(1);
+        self.records.send(CaptureMessage::Flush(done)).ok();
+        wait.recv().ok();
+    }
+
+    fn write(&self, device: &UsbDevice, event: Event) {
+        // in the usbmon header and the pcap record header
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let packet = usbmon_packet(device, &event, now);
+        self.records
+            .send(CaptureMessage::Record(pcap_record(&packet, now)))
+            .ok();
+    }
+}
+
+/// Frame a packet captured at `now` since the epoch as a pcap record, truncated to [PCAP_SNAPLEN]
+fn pcap_record(packet: &[u8], now: Duration) -> Vec<u8> {
+    let captured = &packet[..packet.len().min(PCAP_SNAPLEN as usize)];
+    let mut record = Vec::with_capacity(16 + captured.len());
+    record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(captured.len() as u32).to_le_bytes()); // incl_len
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // orig_len
+    record.extend_from_slice(captured);
+    record
+}
+
+/// The files of a [Capture], owned by its writer thread
+struct CaptureWriter {
+    config: CaptureConfig,
+    file: Option<CaptureFile>,
+}
+
+impl CaptureWriter {
+    fn write_record(&mut self, record: &[u8]) -> std::io::Result<()> {
+        if let (Some(current), Some(max_file_size)) = (&self.file, self.config.max_file_size) {
+            if current.size > PCAP_HEADER_SIZE && current.size + record.len() as u64 > max_file_size
+            {
+                self.file = None;
+                self.rotate()?;
+            }
+        }
+        let current = match &mut self.file {
+            Some(current) => current,
+            None => self.file.insert(self.create()?),
+        };
+        current.file.write_all(record)?;
+        current.size += record.len() as u64;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        let max_files = self.config.max_files;
+        if max_files == 1 {
+            return Ok(());
+        }
+        std::fs::remove_file(self.rotated_path(max_files - 1)).ok();
+        for index in (1..max_files - 1).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.config.path, self.rotated_path(1))
+    }
+
+    fn create(&self) -> std::io::Result<CaptureFile> {
+        let mut file = File::create(&self.config.path)?;
+        let mut header = Vec::with_capacity(PCAP_HEADER_SIZE as usize);
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes()); // magic
+        header.extend_from_slice(&2u16.to_le_bytes()); // version major
+        header.extend_from_slice(&4u16.to_le_bytes()); // version minor
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(CaptureFile {
+            file,
+            size: PCAP_HEADER_SIZE,
+        })
+    }
+}
+
+/// Encode a usbmon event at `now` since the epoch as in the binary interface of Linux
+fn usbmon_packet(device: &UsbDevice, event: &Event, now: Duration) -> Vec<u8> {
+    let ep = event.header.endpoint_address();
+    let is_in = event.header.direction == USBIP_DIR_IN;
+    let attributes = device
+        .find_ep(ep)
+        .map(|(ep, _)| ep.attributes & 0x03)
+        .unwrap_or(EndpointAttributes::Control as u8);
+    // usbmon orders transfer types differently from endpoint descriptors
+    let xfer_type: u8 = match attributes {
+        0 => 2, // Control
+        1 => 0, // Isochronous
+        2 => 3, // Bulk
+        _ => 1, // Interrupt
+    };
+    let is_iso = xfer_type == 0;
+    // IN data is only known on completion, OUT data only on submission
+    let data: &[u8] = match (event.kind, is_in) {
+        (EventType::Submit, false) | (EventType::Complete, true) => event.data,
+        _ => &[],
+    };
+    let flag_data = if !data.is_empty() {
+        0
+    } else if event.kind == EventType::Submit {
+        b'<'
+    } else {
+        b'>'
+    };
+    let setup = event.setup.filter(|_| xfer_type == 2 && ep & 0x7f == 0);
+
+    let mut packet = Vec::with_capacity(
+        USBMON_HEADER_SIZE + event.iso_packets.len() * USBMON_ISO_DESC_SIZE + data.len(),
+    );
+    packet.extend_from_slice(&(event.header.seq_num as u64).to_le_bytes()); // id
+    packet.push(event.kind as u8);
+    packet.push(xfer_type);
+    packet.push(ep);
+    packet.push(device.dev_num as u8);
+    packet.extend_from_slice(&(device.bus_num as u16).to_le_bytes());
+    packet.push(if setup.is_some() { 0 } else { b'-' });
+    packet.push(flag_data);
+    packet.extend_from_slice(&(now.as_secs() as i64).to_le_bytes());
+    packet.extend_from_slice(&(now.subsec_micros() as i32).to_le_bytes());
+    packet.extend_from_slice(&event.status.to_le_bytes());
+    packet.extend_from_slice(&event.length.to_le_bytes());
+    let len_cap = event.iso_packets.len() * USBMON_ISO_DESC_SIZE + data.len();
+    packet.extend_from_slice(&(len_cap as u32).to_le_bytes());
+    if is_iso {
+        packet.extend_from_slice(&event.error_count.to_le_bytes());
+        packet.extend_from_slice(&(event.iso_packets.len() as u32).to_le_bytes());
+    } else {
+        packet.extend_from_slice(&setup.unwrap_or_default());
+    }
+    packet.extend_from_slice(&event.interval.to_le_bytes());
+    packet.extend_from_slice(&event.start_frame.to_le_bytes());
+    packet.extend_from_slice(&event.transfer_flags.to_le_bytes());
+    packet.extend_from_slice(&(event.iso_packets.len() as u32).to_le_bytes());
+    for iso in event.iso_packets {
+        packet.extend_from_slice(&iso.status.to_le_bytes());
+        packet.extend_from_slice(&iso.offset.to_le_bytes());
+        let length = match event.kind {
+            EventType::Submit => iso.length,
+            EventType::Complete => iso.actual_length,
+        };
+        packet.extend_from_slice(&length.to_le_bytes());
+        packet.extend_from_slice(&0u32.to_le_bytes());
+    }
+    packet.extend_from_slice(data);
+    packet
+}
+
+impl UsbDevice {
+    /// Capture all URBs of this device to pcap files
+    ///
+    /// The files use LINKTYPE_USB_LINUX_MMAPPED, which Wireshark decodes like a usbmon capture.
+    pub fn with_capture(mut self, config: CaptureConfig) -> Self {
+        self.capture = Some(Arc::new(Capture::new(config)));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    fn capture_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("usbip-test-{}-{}.pcap", std::process::id(), name))
+    }
+
+    /// Split a pcap file into the packets
+    fn read_packets(path: &std::path::Path) -> Vec<Vec<u8>> {
+        let file = std::fs::read(path).unwrap();
+        assert_eq!(&file[0..4], &0xa1b2c3d4u32.to_le_bytes());
+        assert_eq!(&file[20..24], &LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        let mut packets = vec![];
+        let mut rest = &file[PCAP_HEADER_SIZE as usize..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            packets.push(rest[16..16 + len].to_vec());
+            rest = &rest[16 + len..];
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn capture_urbs() {
+        let path = capture_path("urbs");
+        let handle =
+            UsbIpServer::new_simulated(vec![cdc_device().with_capture(CaptureConfig::new(&path))])
+                .serve("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+        let device = UsbIpClient::connect(handle.local_addr())
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+
+        // GetDescriptor to Device
+        let setup = SetupPacket {
+            request_type: 0x80,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Device as u16) << 8,
+            index: 0,
+            length: 0x40,
+        };
+        device.control_in(setup).await.unwrap();
+        device.bulk_out(0x02, b"hello").await.unwrap();
+        // rejected on submission
+        assert!(device.bulk_in(0x83, 8).await.is_err());
+        let capture = handle.server().devices.lock().unwrap()[0]
+            .capture
+            .clone()
+            .unwrap();
+        capture.flush();
+        handle.shutdown().await;
+
+        let packets = read_packets(&path);
+        assert_eq!(packets.len(), 6);
+
+        // control submission with setup and without data
+        let submit = &packets[0];
+        assert_eq!(submit.len(), USBMON_HEADER_SIZE);
+        assert_eq!(&submit[8..12], &[b'S', 2, 0x80, 0]);
+        assert_eq!(&submit[14..16], &[0, b'<']);
+        assert_eq!(&submit[28..32], &(-EINPROGRESS).to_le_bytes());
+        assert_eq!(&submit[32..36], &0x40u32.to_le_bytes()); // requested
+        assert_eq!(&submit[40..48], &setup.to_bytes());
+
+        // control completion with the device descriptor
+        let complete = &packets[1];
+        assert_eq!(&complete[0..8], &submit[0..8]); // same id
+        assert_eq!(&complete[8..12], &[b'C', 2, 0x80, 0]);
+        assert_eq!(&complete[14..16], &[b'-', 0]);
+        assert_eq!(&complete[28..32], &0i32.to_le_bytes());
+        assert_eq!(&complete[36..40], &0x12u32.to_le_bytes()); // captured
+        assert_eq!(complete.len(), USBMON_HEADER_SIZE + 0x12);
+        assert_eq!(
+            complete[USBMON_HEADER_SIZE + 1],
+            DescriptorType::Device as u8
+        );
+
+        // bulk out data in the submission only
+        assert_eq!(&packets[2][8..12], &[b'S', 3, 0x02, 0]);
+        assert_eq!(&packets[2][USBMON_HEADER_SIZE..], b"hello");
+        assert_eq!(&packets[3][8..12], &[b'C', 3, 0x02, 0]);
+        assert_eq!(&packets[3][32..36], &5u32.to_le_bytes()); // actual length
+        assert_eq!(packets[3].len(), USBMON_HEADER_SIZE);
+
+        // the rejected URB
+        assert_eq!(&packets[4][8..11], &[b'S', 2, 0x83]);
+        assert_eq!(&packets[5][8..11], &[b'C', 2, 0x83]);
+        assert_eq!(&packets[5][28..32], &(-EPIPE).to_le_bytes());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rotate_files() {
+        let path = capture_path("rotate");
+        let capture = Capture::new(
+            CaptureConfig::new(&path)
+                .with_max_file_size(200)
+                .with_max_files(3),
+        );
+        let device = UsbDevice::new(0);
+        let header = UsbIpHeaderBasic {
+            command: USBIP_RET_SUBMIT,
+            seq_num: 1,
+            dev_id: 0,
+            direction: USBIP_DIR_IN,
+            ep: 0,
+        };
+        // each record is 16 + 64 bytes, so two fit into a file
+        for _ in 0..7 {
+            capture.failed(&device, header, -ECONNRESET);
+        }
+        capture.flush();
+
+        assert_eq!(read_packets(&path).len(), 1);
+        assert_eq!(read_packets(&path.with_extension("pcap.1")).len(), 2);
+        assert_eq!(read_packets(&path.with_extension("pcap.2")).len(), 2);
+        assert!(!path.with_extension("pcap.3").exists());
+        for file in [
+            &path,
+            &path.with_extension("pcap.1"),
+            &path.with_extension("pcap.2"),
+        ] {
+            std::fs::remove_file(file).ok();
+        }
+    }
+
+    #[test]
+    fn truncate_long_packets() {
+        let path = capture_path("truncate");
+        let capture = Capture::new(CaptureConfig::new(&path));
+        let ret = RetSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_SUBMIT,
+                seq_num: 1,
+                dev_id: 0,
+                direction: USBIP_DIR_IN,
+                ep: 0,
+            },
+            actual_length: 0x50000,
+            transfer_buffer: vec![0xaa; 0x50000],
+            ..RetSubmit::default()
+        };
+        capture.complete(&UsbDevice::new(0), &ret);
+        capture.flush();
+
+        let file = std::fs::read(&path).unwrap();
+        let record = &file[PCAP_HEADER_SIZE as usize..];
+        assert_eq!(&record[8..12], &PCAP_SNAPLEN.to_le_bytes()); // incl_len
+        let orig_len = USBMON_HEADER_SIZE as u32 + 0x50000;
+        assert_eq!(&record[12..16], &orig_len.to_le_bytes());
+        assert_eq!(read_packets(&path)[0].len(), PCAP_SNAPLEN as usize);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -10,6 +10,12 @@ pub struct UsbCdcAcmHandler {
 /// Sub class code for CDC ACM
 pub const CDC_ACM_SUBCLASS: u8 = 0x02;
 
+impl Default for UsbCdcAcmHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UsbCdcAcmHandler {
     pub fn new() -> Self {
         Self { tx_buffer: vec![] }
@@ -47,9 +53,10 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
+        transfer_buffer_length: u32,
         _setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult<Vec<u8>> {
         if ep.attributes == EndpointAttributes::Interrupt as u8 {
             // interrupt
             if let Direction::In = ep.direction() {
@@ -63,22 +70,20 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
                 info!(
                     "Got bulk out: {:?} \"{}\"",
                     req,
-                    String::from_utf8_lossy(&req)
+                    String::from_utf8_lossy(req)
                 );
                 return Ok(vec![]);
             } else {
-                // bulk in
-                // TODO: handle max packet size
-                let resp = self.tx_buffer.clone();
-                self.tx_buffer.clear();
-                return Ok(resp);
+                // bulk in, the rest is left for the next URB
+                let len = self.tx_buffer.len().min(transfer_buffer_length as usize);
+                return Ok(self.tx_buffer.drain(..len).collect());
             }
         }
         Ok(vec![])
     }
 
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        return vec![
+        vec![
             // Header
             0x05, // bFunctionLength
             0x24, // CS_INTERFACE
@@ -89,7 +94,7 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
             0x24, // CS_INTERFACE
             0x02, // ACM
             0x00, // Capabilities
-        ];
+        ]
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
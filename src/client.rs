@@ -0,0 +1,395 @@
+//! USB/IP client
+use super::*;
+use protocol::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::oneshot;
+
+/// A connection to a USB/IP server
+pub struct UsbIpClient<T> {
+    socket: T,
+}
+
+impl UsbIpClient<TcpStream> {
+    /// Connect to a USB/IP server at `addr`
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        socket.set_nodelay(true)?;
+        Ok(Self::new(socket))
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static> UsbIpClient<T> {
+    /// Use an established connection to a USB/IP server
+    pub fn new(socket: T) -> Self {
+        Self { socket }
+    }
+
+    /// List exported devices with OP_REQ_DEVLIST
+    ///
+    /// Linux usbipd closes the connection afterwards, connect again to import a device
+    pub async fn list_devices(&mut self) -> Result<Vec<(UsbDeviceInfo, Vec<UsbInterfaceInfo>)>> {
+        OpReqDevlist::default().write(&mut self.socket).await?;
+        let rep = OpRepDevlist::read(&mut self.socket).await?;
+        if rep.status != 0 {
+            return Err(std::io::Error::other(format!(
+                "OP_REQ_DEVLIST failed with status {}",
+                rep.status
+            )));
+        }
+        Ok(rep.devices)
+    }
+
+    /// Import the device at `bus_id` with OP_REQ_IMPORT
+    ///
+    /// The connection is then used to submit URBs to the device
+    pub async fn import(mut self, bus_id: &str) -> Result<UsbIpClientDevice<T>> {
+        OpReqImport {
+            bus_id: bus_id.to_string(),
+            ..OpReqImport::default()
+        }
+        .write(&mut self.socket)
+        .await?;
+        let rep = OpRepImport::read(&mut self.socket).await?;
+        match rep.device {
+            Some(info) if rep.status == 0 => Ok(UsbIpClientDevice::new(self.socket, info)),
+            _ => Err(std::io::Error::other(format!(
+                "OP_REQ_IMPORT of {} failed with status {}",
+                bus_id, rep.status
+            ))),
+        }
+    }
+}
+
+/// URBs waiting for USBIP_RET_SUBMIT, by seq_num
+#[derive(Default)]
+struct ClientState {
+    /// Direction is needed to decode the reply
+    pending: HashMap<u32, (Direction, oneshot::Sender<RetSubmit>)>,
+    /// seq_num of USBIP_CMD_UNLINK to seq_num of the URB
+    unlinking: HashMap<u32, u32>,
+}
+
+struct ClientShared<T> {
+    writer: tokio::sync::Mutex<WriteHalf<T>>,
+    state: Mutex<ClientState>,
+    seq_num: AtomicU32,
+    dev_id: u32,
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin> ClientShared<T> {
+    fn next_seq_num(&self) -> u32 {
+        self.seq_num.fetch_add(1, Ordering::Relaxed)
+    }
+
+    async fn unlink(&self, header: UsbIpHeaderBasic) -> Result<()> {
+        let seq_num = self.next_seq_num();
+        self.state
+            .lock()
+            .unwrap()
+            .unlinking
+            .insert(seq_num, header.seq_num);
+        let cmd = CmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK,
+                seq_num,
+                ..header
+            },
+            unlink_seq_num: header.seq_num,
+        };
+        let mut writer = self.writer.lock().await;
+        cmd.write(&mut *writer).await
+    }
+}
+
+/// Unlinks a submitted URB when dropped before completion, e.g. on timeout
+struct UnlinkGuard<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static> {
+    shared: Arc<ClientShared<T>>,
+    header: Option<UsbIpHeaderBasic>,
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static> Drop for UnlinkGuard<T> {
+    fn drop(&mut self) {
+        if let Some(header) = self.header.take() {
+            let shared = self.shared.clone();
+            tokio::spawn(async move {
+                if let Err(err) = shared.unlink(header).await {
+                    warn!("Failed to unlink URB {}: {}", header.seq_num, err);
+                }
+            });
+        }
+    }
+}
+
+/// A device imported from a USB/IP server
+///
+/// Transfers can be issued concurrently. Dropping a transfer before it completes sends USBIP_CMD_UNLINK for it.
+pub struct UsbIpClientDevice<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static> {
+    info: UsbDeviceInfo,
+    shared: Arc<ClientShared<T>>,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static> UsbIpClientDevice<T> {
+    fn new(socket: T, info: UsbDeviceInfo) -> Self {
+        let (reader, writer) = tokio::io::split(socket);
+        let shared = Arc::new(ClientShared {
+            writer: tokio::sync::Mutex::new(writer),
+            state: Mutex::new(ClientState::default()),
+            seq_num: AtomicU32::new(1),
+            dev_id: info.bus_num << 16 | info.dev_num,
+        });
+        let reader = tokio::spawn(Self::read_replies(reader, shared.clone()));
+        Self {
+            info,
+            shared,
+            reader,
+        }
+    }
+
+    /// Description of the device from OP_REP_IMPORT
+    pub fn info(&self) -> &UsbDeviceInfo {
+        &self.info
+    }
+
+    async fn read_replies(mut socket: ReadHalf<T>, shared: Arc<ClientShared<T>>) {
+        let res: Result<()> = async {
+            loop {
+                let header = UsbIpHeaderBasic::read(&mut socket).await?;
+                match header.command {
+                    USBIP_RET_SUBMIT => {
+                        let pending = shared.state.lock().unwrap().pending.remove(&header.seq_num);
+                        let Some((direction, sender)) = pending else {
+                            return Err(std::io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("unexpected USBIP_RET_SUBMIT {}", header.seq_num),
+                            ));
+                        };
+                        let ret = RetSubmit::read_body(&mut socket, header, direction).await?;
+                        // the transfer may have been dropped
+                        sender.send(ret).ok();
+                    }
+                    USBIP_RET_UNLINK => {
+                        let ret = RetUnlink::read_body(&mut socket, header).await?;
+                        let mut state = shared.state.lock().unwrap();
+                        if let Some(seq_num) = state.unlinking.remove(&header.seq_num) {
+                            trace!("URB {} unlinked with status {}", seq_num, ret.status);
+                            if ret.status != 0 {
+                                // no USBIP_RET_SUBMIT follows
+                                state.pending.remove(&seq_num);
+                            }
+                        }
+                    }
+                    command => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("unknown command {:x}", command),
+                        ))
+                    }
+                }
+            }
+        }
+        .await;
+        info!("Client connection ended with {:?}", res);
+        // fail all pending transfers
+        shared.state.lock().unwrap().pending.clear();
+    }
+
+    /// Submit a URB and wait for its completion
+    async fn submit(
+        &self,
+        ep: u8,
+        direction: Direction,
+        setup: [u8; 8],
+        transfer_buffer_length: u32,
+        out_data: &[u8],
+    ) -> UrbResult<RetSubmit> {
+        let seq_num = self.shared.next_seq_num();
+        let header = UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT,
+            seq_num,
+            dev_id: self.shared.dev_id,
+            direction: match direction {
+                Direction::In => USBIP_DIR_IN,
+                Direction::Out => USBIP_DIR_OUT,
+            },
+            ep: (ep & 0x7F) as u32,
+        };
+        let cmd = CmdSubmit {
+            header,
+            transfer_flags: match direction {
+                Direction::In => USBIP_URB_DIR_IN,
+                Direction::Out => 0,
+            },
+            transfer_buffer_length,
+            setup,
+            transfer_buffer: out_data.to_vec(),
+            ..CmdSubmit::default()
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .pending
+            .insert(seq_num, (direction, tx));
+        let mut guard = UnlinkGuard {
+            shared: self.shared.clone(),
+            header: None,
+        };
+        {
+            let mut writer = self.shared.writer.lock().await;
+            cmd.write(&mut *writer).await?;
+        }
+        guard.header = Some(header);
+
+        let res = rx.await;
+        guard.header = None;
+        let ret = res.map_err(|_| std::io::Error::from(ErrorKind::ConnectionAborted))?;
+        match UrbError::from_status(ret.status) {
+            Some(err) => Err(err),
+            None => Ok(ret),
+        }
+    }
+
+    /// Control transfer with IN data stage, return the data
+    pub async fn control_in(&self, setup: SetupPacket) -> UrbResult<Vec<u8>> {
+        let ret = self
+            .submit(
+                0x80,
+                Direction::In,
+                setup.to_bytes(),
+                setup.length as u32,
+                &[],
+            )
+            .await?;
+        Ok(ret.transfer_buffer)
+    }
+
+    /// Control transfer with OUT or no data stage, return the transferred length
+    pub async fn control_out(&self, setup: SetupPacket, data: &[u8]) -> UrbResult<usize> {
+        let ret = self
+            .submit(
+                0x00,
+                Direction::Out,
+                setup.to_bytes(),
+                data.len() as u32,
+                data,
+            )
+            .await?;
+        Ok(ret.actual_length as usize)
+    }
+
+    /// Bulk IN transfer of at most `length` bytes from endpoint `ep`
+    pub async fn bulk_in(&self, ep: u8, length: u32) -> UrbResult<Vec<u8>> {
+        let ret = self.submit(ep, Direction::In, [0; 8], length, &[]).await?;
+        Ok(ret.transfer_buffer)
+    }
+
+    /// Bulk OUT transfer to endpoint `ep`, return the transferred length
+    pub async fn bulk_out(&self, ep: u8, data: &[u8]) -> UrbResult<usize> {
+        let ret = self
+            .submit(ep, Direction::Out, [0; 8], data.len() as u32, data)
+            .await?;
+        Ok(ret.actual_length as usize)
+    }
+
+    /// Interrupt IN transfer of at most `length` bytes from endpoint `ep`
+    pub async fn interrupt_in(&self, ep: u8, length: u32) -> UrbResult<Vec<u8>> {
+        // same as bulk on the wire, the server knows the endpoint type
+        self.bulk_in(ep, length).await
+    }
+
+    /// Interrupt OUT transfer to endpoint `ep`, return the transferred length
+    pub async fn interrupt_out(&self, ep: u8, data: &[u8]) -> UrbResult<usize> {
+        self.bulk_out(ep, data).await
+    }
+}
+
+impl<T: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static> Drop for UsbIpClientDevice<T> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    async fn spawn_cdc_server() -> (SocketAddr, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) {
+        let device = cdc_device();
+        let handler = device.interfaces()[0].handler.clone();
+        let server = UsbIpServer::new_simulated(vec![device]);
+        let addr = server
+            .serve((Ipv4Addr::LOCALHOST, 0).into())
+            .await
+            .unwrap()
+            .local_addr();
+        (addr, handler)
+    }
+
+    #[tokio::test]
+    async fn list_and_import() {
+        let (addr, handler) = spawn_cdc_server().await;
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let mut client = UsbIpClient::connect(addr).await.unwrap();
+        let devices = client.list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].0.bus_id, "0");
+        assert_eq!(devices[0].1[0].interface_class, ClassCode::CDC as u8);
+
+        let device = client.import("0").await.unwrap();
+        assert_eq!(device.info().bus_id, "0");
+
+        // GetDescriptor to Device
+        let desc = device
+            .control_in(SetupPacket {
+                request_type: 0x80,
+                request: StandardRequest::GetDescriptor as u8,
+                value: (DescriptorType::Device as u16) << 8,
+                index: 0,
+                length: 0x40,
+            })
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 0x12);
+        verify_descriptor(&desc);
+
+        assert_eq!(device.bulk_out(0x02, b"hello").await.unwrap(), 5);
+
+        handler
+            .lock()
+            .unwrap()
+            .as_any()
+            .downcast_mut::<cdc::UsbCdcAcmHandler>()
+            .unwrap()
+            .tx_buffer
+            .extend_from_slice(b"world");
+        assert_eq!(device.bulk_in(0x82, 512).await.unwrap(), b"world");
+        assert_eq!(device.interrupt_in(0x81, 8).await.unwrap(), b"");
+
+        // GetDescriptor to OTG is unsupported
+        let res = device
+            .control_in(SetupPacket {
+                request_type: 0x80,
+                request: StandardRequest::GetDescriptor as u8,
+                value: (DescriptorType::OTG as u16) << 8,
+                index: 0,
+                length: 0x40,
+            })
+            .await;
+        assert!(matches!(res, Err(UrbError::Stall)));
+    }
+
+    #[tokio::test]
+    async fn import_unknown_device() {
+        let (addr, _handler) = spawn_cdc_server().await;
+        let client = UsbIpClient::connect(addr).await.unwrap();
+        assert!(client.import("1-1").await.is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use super::*;
+
+/// Represent a USB configuration
+#[derive(Clone)]
+pub struct UsbConfiguration {
+    /// bConfigurationValue, non-zero
+    pub configuration_value: u8,
+    /// bmAttributes: bus powered, self powered, remote wakeup
+    pub attributes: u8,
+    /// bMaxPower in 2 mA
+    pub max_power: u8,
+    pub string_configuration: u8,
+    pub interfaces: Vec<UsbInterface>,
+}
+
+/// bmAttributes of a configuration: bit 7 must be set
+pub const CONFIGURATION_BUS_POWERED: u8 = 0x80;
+/// bmAttributes of a configuration: the device is self powered
+pub const CONFIGURATION_SELF_POWERED: u8 = 0x40;
+/// bmAttributes of a configuration: the device supports remote wakeup
+pub const CONFIGURATION_REMOTE_WAKEUP: u8 = 0x20;
+
+impl UsbConfiguration {
+    /// Configuration descriptor followed by the interface, class specific and endpoint descriptors
+    pub(crate) fn descriptor(&self) -> Vec<u8> {
+        use DescriptorType::*;
+
+        let mut desc = vec![
+            0x09,                // bLength
+            Configuration as u8, // bDescriptorType: Configuration
+            0x00,
+            0x00,                        // wTotalLength: to be filled below
+            self.interfaces.len() as u8, // bNumInterfaces
+            self.configuration_value,    // bConfigurationValue
+            self.string_configuration,   // iConfiguration
+            self.attributes,             // bmAttributes
+            self.max_power,              // bMaxPower
+        ];
+        for (i, intf) in self.interfaces.iter().enumerate() {
+            for (alternate_setting, setting) in intf.alternate_settings.iter().enumerate() {
+                let mut intf_desc = vec![
+                    0x09,                          // bLength
+                    Interface as u8,               // bDescriptorType: Interface
+                    i as u8,                       // bInterfaceNum
+                    alternate_setting as u8,       // bAlternateSettings
+                    setting.endpoints.len() as u8, // bNumEndpoints
+                    setting.interface_class,       // bInterfaceClass
+                    setting.interface_subclass,    // bInterfaceSubClass
+                    setting.interface_protocol,    // bInterfaceProtocol
+                    setting.string_interface,      //iInterface
+                ];
+                // class specific endpoint
+                let mut specific = setting.class_specific_descriptor.clone();
+                intf_desc.append(&mut specific);
+                // endpoint descriptors
+                for endpoint in &setting.endpoints {
+                    let mut ep_desc = vec![
+                        0x07,                // bLength
+                        Endpoint as u8,      // bDescriptorType: Endpoint
+                        endpoint.address,    // bEndpointAddress
+                        endpoint.attributes, // bmAttributes
+                        endpoint.max_packet_size as u8,
+                        (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
+                        endpoint.interval,                     // bInterval
+                    ];
+                    intf_desc.append(&mut ep_desc);
+                }
+                desc.append(&mut intf_desc);
+            }
+        }
+        // length
+        let len = desc.len() as u16;
+        desc[2] = len as u8;
+        desc[3] = (len >> 8) as u8;
+        desc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remembers whether its configuration is selected and its alternate setting
+    #[derive(Default)]
+    struct ConfiguredHandler {
+        configured: bool,
+        alternate_setting: u8,
+    }
+
+    impl UsbInterfaceHandler for ConfiguredHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> UrbResult<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn set_configured(&mut self, _interface: &UsbInterface, configured: bool) {
+            self.configured = configured;
+        }
+
+        fn set_alternate_setting(
+            &mut self,
+            _interface: &UsbInterface,
+            _number: u8,
+            alternate_setting: u8,
+        ) -> UrbResult<()> {
+            self.alternate_setting = alternate_setting;
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn configured_handler() -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+        Arc::new(Mutex::new(
+            Box::new(ConfiguredHandler::default()) as Box<dyn UsbInterfaceHandler + Send>
+        ))
+    }
+
+    fn configured(handler: &Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) -> bool {
+        let mut handler = handler.lock().unwrap();
+        handler
+            .as_any()
+            .downcast_mut::<ConfiguredHandler>()
+            .unwrap()
+            .configured
+    }
+
+    fn alternate_setting(handler: &Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>) -> u8 {
+        let mut handler = handler.lock().unwrap();
+        handler
+            .as_any()
+            .downcast_mut::<ConfiguredHandler>()
+            .unwrap()
+            .alternate_setting
+    }
+
+    fn bulk_out_endpoint(address: u8) -> Vec<UsbEndpoint> {
+        vec![UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        }]
+    }
+
+    fn standard_request(request_type: u8, request: StandardRequest, value: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request: request as u8,
+            value,
+            index: 0,
+            length: if request_type & 0x80 != 0 { 0xff } else { 0 },
+        }
+    }
+
+    #[tokio::test]
+    async fn switch_configurations() {
+        use StandardRequest::*;
+
+        let handlers = [configured_handler(), configured_handler()];
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "First",
+                bulk_out_endpoint(0x01),
+                handlers[0].clone(),
+            )
+            .with_configuration(
+                "Second Configuration",
+                CONFIGURATION_BUS_POWERED | CONFIGURATION_SELF_POWERED,
+                0,
+            )
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Second",
+                bulk_out_endpoint(0x02),
+                handlers[1].clone(),
+            )]);
+        let (client, server_stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(server_stream, Arc::new(server)));
+        let device = UsbIpClient::new(client).import("0").await.unwrap();
+
+        let desc = device
+            .control_in(standard_request(
+                0x80,
+                GetDescriptor,
+                (DescriptorType::Device as u16) << 8,
+            ))
+            .await
+            .unwrap();
+        // bNumConfigurations
+        assert_eq!(desc[17], 2);
+        let desc = device
+            .control_in(standard_request(
+                0x80,
+                GetDescriptor,
+                (DescriptorType::Configuration as u16) << 8 | 1,
+            ))
+            .await
+            .unwrap();
+        verify_descriptor(&desc);
+        // bConfigurationValue, iConfiguration, bmAttributes, bMaxPower
+        assert_eq!(desc[5..9], [2, 6, 0xC0, 0]);
+        let res = device
+            .control_in(standard_request(
+                0x80,
+                GetDescriptor,
+                (DescriptorType::Configuration as u16) << 8 | 2,
+            ))
+            .await;
+        assert!(matches!(res, Err(UrbError::Stall)));
+
+        let get_configuration = standard_request(0x80, GetConfiguration, 0);
+        assert_eq!(device.control_in(get_configuration).await.unwrap(), [1]);
+        let res = device
+            .control_out(standard_request(0x00, SetConfiguration, 3), &[])
+            .await;
+        assert!(matches!(res, Err(UrbError::Stall)));
+
+        device
+            .control_out(standard_request(0x00, SetConfiguration, 2), &[])
+            .await
+            .unwrap();
+        assert_eq!(device.control_in(get_configuration).await.unwrap(), [2]);
+        assert!(!configured(&handlers[0]));
+        assert!(configured(&handlers[1]));
+        // endpoints of the first configuration are gone
+        assert_eq!(device.bulk_out(0x02, b"hello").await.unwrap(), 5);
+        assert!(matches!(
+            device.bulk_out(0x01, b"hello").await,
+            Err(UrbError::Stall)
+        ));
+
+        // unconfigured
+        device
+            .control_out(standard_request(0x00, SetConfiguration, 0), &[])
+            .await
+            .unwrap();
+        assert_eq!(device.control_in(get_configuration).await.unwrap(), [0]);
+        assert!(!configured(&handlers[1]));
+    }
+
+    #[tokio::test]
+    async fn switch_alternate_settings() {
+        use StandardRequest::*;
+
+        let handler = configured_handler();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0x00,
+                0x00,
+                "Idle",
+                vec![],
+                handler.clone(),
+            )
+            .with_alternate_setting(
+                ClassCode::VendorSpecific as u8,
+                0x01,
+                0x00,
+                "Streaming",
+                bulk_out_endpoint(0x01),
+                vec![],
+            )]);
+        let (client, server_stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(server_stream, Arc::new(server)));
+        let device = UsbIpClient::new(client).import("0").await.unwrap();
+
+        let desc = device
+            .control_in(standard_request(
+                0x80,
+                GetDescriptor,
+                (DescriptorType::Configuration as u16) << 8,
+            ))
+            .await
+            .unwrap();
+        verify_descriptor(&desc);
+        // bNumInterfaces
+        assert_eq!(desc[4], 1);
+        // bInterfaceNumber, bAlternateSetting, bNumEndpoints of both settings
+        assert_eq!(desc[11..14], [0, 0, 0]);
+        assert_eq!(desc[20..23], [0, 1, 1]);
+
+        let get_interface = standard_request(0x81, GetInterface, 0);
+        assert_eq!(device.control_in(get_interface).await.unwrap(), [0]);
+        // endpoints of other alternate settings are disabled
+        assert!(matches!(
+            device.bulk_out(0x01, b"hello").await,
+            Err(UrbError::Stall)
+        ));
+
+        device
+            .control_out(standard_request(0x01, SetInterface, 1), &[])
+            .await
+            .unwrap();
+        assert_eq!(device.control_in(get_interface).await.unwrap(), [1]);
+        assert_eq!(alternate_setting(&handler), 1);
+        assert_eq!(device.bulk_out(0x01, b"hello").await.unwrap(), 5);
+
+        let res = device
+            .control_out(standard_request(0x01, SetInterface, 2), &[])
+            .await;
+        assert!(matches!(res, Err(UrbError::Stall)));
+        assert_eq!(device.control_in(get_interface).await.unwrap(), [1]);
+
+        // selecting the configuration again resets the alternate setting
+        device
+            .control_out(standard_request(0x00, SetConfiguration, 1), &[])
+            .await
+            .unwrap();
+        assert_eq!(device.control_in(get_interface).await.unwrap(), [0]);
+    }
+}
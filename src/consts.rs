@@ -1,7 +1,7 @@
 use super::*;
 
 /// A list of known USB speeds
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum UsbSpeed {
     Unknown = 0x0,
     Low,
@@ -67,8 +67,8 @@ pub enum StandardRequest {
     GetConfiguration = 8,
     SetConfiguration = 9,
     GetInterface = 0xA,
-    SetInterface = 0x11,
-    SynthFrame = 0x12,
+    SetInterface = 0xB,
+    SynthFrame = 0xC,
 }
 
 /// A list of defined USB descriptor types
@@ -1,4 +1,5 @@
 use super::*;
+use protocol::{UsbDeviceInfo, UsbInterfaceInfo};
 use rusb::Version as rusbVersion;
 
 #[derive(Clone, Default)]
@@ -18,9 +19,9 @@ impl From<rusbVersion> for Version {
     }
 }
 
-impl Into<rusbVersion> for Version {
-    fn into(self) -> rusbVersion {
-        rusbVersion(self.major, self.minor, self.patch)
+impl From<Version> for rusbVersion {
+    fn from(val: Version) -> Self {
+        rusbVersion(val.major, val.minor, val.patch)
     }
 }
 
@@ -38,9 +39,7 @@ pub struct UsbDevice {
     pub device_class: u8,
     pub device_subclass: u8,
     pub device_protocol: u8,
-    pub configuration_value: u8,
-    pub num_configurations: u8,
-    pub interfaces: Vec<UsbInterface>,
+    pub configurations: Vec<UsbConfiguration>,
     pub device_handler: Option<Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>>,
     pub usb_version: Version,
 
@@ -48,10 +47,15 @@ pub struct UsbDevice {
     pub(crate) ep0_out: UsbEndpoint,
     // strings
     pub(crate) string_pool: HashMap<u8, String>,
-    pub(crate) string_configuration: u8,
     pub(crate) string_manufacturer: u8,
     pub(crate) string_product: u8,
     pub(crate) string_serial: u8,
+    pub(crate) capture: Option<Arc<Capture>>,
+    pub(crate) limits: Option<UrbLimits>,
+    /// Outstanding URBs across connections
+    pub(crate) usage: Arc<Mutex<UrbUsage>>,
+    /// bConfigurationValue of the active configuration, zero if unconfigured
+    pub(crate) active_configuration: Arc<Mutex<u8>>,
 }
 
 impl UsbDevice {
@@ -74,17 +78,36 @@ impl UsbDevice {
                 interval: 0,
             },
             // configured by default
-            configuration_value: 1,
-            num_configurations: 1,
+            active_configuration: Arc::new(Mutex::new(1)),
             ..Self::default()
         };
-        res.string_configuration = res.new_string("Default Configuration");
+        res = res.with_configuration(
+            "Default Configuration",
+            CONFIGURATION_BUS_POWERED,
+            0x32, // 100mA
+        );
         res.string_manufacturer = res.new_string("Manufacturer");
         res.string_product = res.new_string("Product");
         res.string_serial = res.new_string("Serial");
         res
     }
 
+    /// Add a configuration, following [UsbDevice::with_interface] calls add interfaces to it
+    ///
+    /// Configurations are numbered from 1, [UsbDevice::new] adds configuration 1 and selects it
+    pub fn with_configuration(mut self, name: &str, attributes: u8, max_power: u8) -> Self {
+        let string_configuration = self.new_string(name);
+        self.configurations.push(UsbConfiguration {
+            configuration_value: self.configurations.len() as u8 + 1,
+            attributes,
+            max_power,
+            string_configuration,
+            interfaces: vec![],
+        });
+        self
+    }
+
+    /// Add an interface to the last configuration
     pub fn with_interface(
         mut self,
         interface_class: u8,
@@ -96,14 +119,47 @@ impl UsbDevice {
     ) -> Self {
         let string_interface = self.new_string(name);
         let class_specific_descriptor = handler.lock().unwrap().get_class_specific_descriptor();
-        self.interfaces.push(UsbInterface {
+        let configuration = self
+            .configurations
+            .last_mut()
+            .expect("no configuration to add the interface to");
+        let setting = UsbAlternateSetting {
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            endpoints,
+            string_interface,
+            class_specific_descriptor,
+        };
+        configuration
+            .interfaces
+            .push(UsbInterface::new(vec![setting], handler));
+        self
+    }
+
+    /// Add an alternate setting to the last interface, numbered from 1 after the one of [UsbDevice::with_interface]
+    pub fn with_alternate_setting(
+        mut self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        name: &str,
+        endpoints: Vec<UsbEndpoint>,
+        class_specific_descriptor: Vec<u8>,
+    ) -> Self {
+        let string_interface = self.new_string(name);
+        let interface = self
+            .configurations
+            .last_mut()
+            .and_then(|configuration| configuration.interfaces.last_mut())
+            .expect("no interface to add the alternate setting to");
+        interface.alternate_settings.push(UsbAlternateSetting {
             interface_class,
             interface_subclass,
             interface_protocol,
             endpoints,
             string_interface,
             class_specific_descriptor,
-            handler,
         });
         self
     }
@@ -118,22 +174,69 @@ impl UsbDevice {
 
     pub(crate) fn new_string(&mut self, s: &str) -> u8 {
         for i in 1.. {
-            if self.string_pool.get(&i).is_none() {
-                self.string_pool.insert(i, s.to_string());
+            if let std::collections::hash_map::Entry::Vacant(e) = self.string_pool.entry(i) {
+                e.insert(s.to_string());
                 return i;
             }
         }
         panic!("string poll exhausted")
     }
 
+    /// bConfigurationValue of the active configuration, zero if unconfigured
+    pub fn configuration_value(&self) -> u8 {
+        *self.active_configuration.lock().unwrap()
+    }
+
+    /// The configuration selected by SET_CONFIGURATION, `None` if unconfigured
+    pub fn active_configuration(&self) -> Option<&UsbConfiguration> {
+        let value = self.configuration_value();
+        self.configurations
+            .iter()
+            .find(|configuration| configuration.configuration_value == value)
+    }
+
+    /// Interfaces of the active configuration
+    pub fn interfaces(&self) -> &[UsbInterface] {
+        self.active_configuration()
+            .map_or(&[], |configuration| &configuration.interfaces)
+    }
+
+    /// Switch to the configuration of `value`, zero to unconfigure the device
+    ///
+    /// The device handler may refuse it, otherwise interface handlers of the old, then the new configuration are notified
+    pub(crate) fn set_configuration(&self, value: u8) -> UrbResult<()> {
+        if value != 0
+            && !self
+                .configurations
+                .iter()
+                .any(|configuration| configuration.configuration_value == value)
+        {
+            warn!("unknown configuration {}", value);
+            return Err(UrbError::Stall);
+        }
+        if let Some(handler) = &self.device_handler {
+            handler.lock().unwrap().set_configuration(value)?;
+        }
+        info!("Set configuration of device {} to {}", self.bus_id, value);
+        for intf in self.interfaces() {
+            intf.handler.lock().unwrap().set_configured(intf, false);
+        }
+        *self.active_configuration.lock().unwrap() = value;
+        for intf in self.interfaces() {
+            *intf.active_setting.lock().unwrap() = 0;
+            intf.handler.lock().unwrap().set_configured(intf, true);
+        }
+        Ok(())
+    }
+
     pub(crate) fn find_ep(&self, ep: u8) -> Option<(UsbEndpoint, Option<&UsbInterface>)> {
         if ep == self.ep0_in.address {
             Some((self.ep0_in, None))
         } else if ep == self.ep0_out.address {
             Some((self.ep0_out, None))
         } else {
-            for intf in &self.interfaces {
-                for endpoint in &intf.endpoints {
+            for intf in self.interfaces() {
+                for endpoint in intf.endpoints() {
                     if endpoint.address == ep {
                         return Some((*endpoint, Some(intf)));
                     }
@@ -143,309 +246,324 @@ impl UsbDevice {
         }
     }
 
-    pub(crate) async fn write_dev<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-        &self,
-        socket: &mut T,
-    ) -> Result<()> {
-        socket_write_fixed_string(socket, &self.path, 256).await?;
-        socket_write_fixed_string(socket, &self.bus_id, 32).await?;
-
-        // fields
-        socket.write_u32(self.bus_num).await?;
-        socket.write_u32(self.dev_num).await?;
-        socket.write_u32(self.speed).await?;
-        socket.write_u16(self.vendor_id).await?;
-        socket.write_u16(self.product_id).await?;
-        socket
-            .write_u16((self.device_bcd.major as u16) << 8 | self.device_bcd.minor as u16)
-            .await?;
-        socket.write_u8(self.device_class).await?;
-        socket.write_u8(self.device_subclass).await?;
-        socket.write_u8(self.device_protocol).await?;
-        socket.write_u8(self.configuration_value).await?;
-        socket.write_u8(self.num_configurations).await?;
-        socket.write_u8(self.interfaces.len() as u8).await?;
+    /// Description of this device in OP_REP_DEVLIST and OP_REP_IMPORT
+    pub fn device_info(&self) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            path: self.path.clone(),
+            bus_id: self.bus_id.clone(),
+            bus_num: self.bus_num,
+            dev_num: self.dev_num,
+            speed: self.speed,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            device_bcd: (self.device_bcd.major as u16) << 8 | self.device_bcd.minor as u16,
+            device_class: self.device_class,
+            device_subclass: self.device_subclass,
+            device_protocol: self.device_protocol,
+            configuration_value: self.configuration_value(),
+            num_configurations: self.configurations.len() as u8,
+            num_interfaces: self.interfaces().len() as u8,
+        }
+    }
 
-        Ok(())
+    /// Description of the interfaces of this device in OP_REP_DEVLIST
+    pub fn interface_info(&self) -> Vec<UsbInterfaceInfo> {
+        self.interfaces()
+            .iter()
+            .filter_map(UsbInterface::active_setting)
+            .map(|setting| UsbInterfaceInfo {
+                interface_class: setting.interface_class,
+                interface_subclass: setting.interface_subclass,
+                interface_protocol: setting.interface_protocol,
+            })
+            .collect()
     }
 
-    pub(crate) async fn write_dev_with_interfaces<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    pub(crate) fn handle_urb(
         &self,
-        socket: &mut T,
-    ) -> Result<()> {
-        self.write_dev(socket).await?;
+        ep: UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        transfer_buffer_length: u32,
+        setup: [u8; 8],
+        out_data: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        use EndpointAttributes::*;
 
-        for interface in &self.interfaces {
-            socket.write_u8(interface.interface_class).await?;
-            socket.write_u8(interface.interface_subclass).await?;
-            socket.write_u8(interface.interface_protocol).await?;
-            // padding
-            socket.write_u8(0).await?;
+        match FromPrimitive::from_u8(ep.attributes) {
+            Some(Control) => self.handle_control_urb(ep, transfer_buffer_length, setup, out_data),
+            Some(_) => {
+                // others
+                let intf = intf.ok_or(UrbError::Stall)?;
+                let mut handler = intf.handler.lock().unwrap();
+                let resp = handler.handle_urb(
+                    intf,
+                    ep,
+                    transfer_buffer_length,
+                    SetupPacket::parse(&setup),
+                    out_data,
+                )?;
+                Ok(resp)
+            }
+            _ => {
+                warn!("unknown transfer type of {:?}", ep);
+                Err(UrbError::Stall)
+            }
         }
-        Ok(())
     }
 
-    pub(crate) async fn handle_urb<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    /// Handle a control transfer
+    ///
+    /// The data stage follows bmRequestType and wLength, `ep` with the direction of the USB/IP header must agree
+    fn handle_control_urb(
         &self,
-        socket: &mut T,
         ep: UsbEndpoint,
-        intf: Option<&UsbInterface>,
         transfer_buffer_length: u32,
         setup: [u8; 8],
-    ) -> Result<Vec<u8>> {
-        use DescriptorType::*;
-        use Direction::*;
-        use EndpointAttributes::*;
+        out_data: &[u8],
+    ) -> UrbResult<Vec<u8>> {
         use StandardRequest::*;
 
-        // parse setup
         let setup_packet = SetupPacket::parse(&setup);
-
-        // read data from socket for OUT
-        let out_data = if let Out = ep.direction() {
-            let mut data = vec![0u8; transfer_buffer_length as usize];
-            socket.read_exact(&mut data).await?;
-            data
-        } else {
-            vec![]
+        let direction = setup_packet.direction();
+        debug!("Control {:?} setup={:x?}", direction, setup_packet);
+        match direction {
+            Direction::In if ep.direction() != Direction::In => {
+                warn!("control IN data stage in an OUT URB: {:x?}", setup_packet);
+                return Err(UrbError::Stall);
+            }
+            Direction::Out if out_data.len() != setup_packet.length as usize => {
+                warn!(
+                    "control OUT data stage of {} bytes with {} bytes of data: {:x?}",
+                    setup_packet.length,
+                    out_data.len(),
+                    setup_packet
+                );
+                return Err(UrbError::Stall);
+            }
+            _ => {}
+        }
+        // endpoint zero in the direction of the data stage
+        let ep = UsbEndpoint {
+            address: match direction {
+                Direction::In => ep.address | 0x80,
+                Direction::Out => ep.address & 0x7F,
+            },
+            ..ep
         };
 
-        match (FromPrimitive::from_u8(ep.attributes), ep.direction()) {
-            (Some(Control), In) => {
-                // control in
-                debug!("Control IN setup={:x?}", setup_packet);
-                match (
-                    setup_packet.request_type,
-                    FromPrimitive::from_u8(setup_packet.request),
-                ) {
-                    (0b10000000, Some(GetDescriptor)) => {
-                        // high byte: type
-                        match FromPrimitive::from_u16(setup_packet.value >> 8) {
-                            Some(Device) => {
-                                debug!("Get device descriptor");
-                                let mut desc = vec![
-                                    0x12,         // bLength
-                                    Device as u8, // bDescriptorType: Device
-                                    self.usb_version.minor as u8,
-                                    self.usb_version.major as u8, // bcdUSB: USB 2.0
-                                    self.device_class,            // bDeviceClass
-                                    self.device_subclass,         // bDeviceSubClass
-                                    self.device_protocol,         // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.vendor_id as u8,         // idVendor
-                                    (self.vendor_id >> 8) as u8,
-                                    self.product_id as u8, // idProduct
-                                    (self.product_id >> 8) as u8,
-                                    self.device_bcd.minor as u8, // bcdDevice
-                                    self.device_bcd.major as u8,
-                                    self.string_manufacturer, // iManufacturer
-                                    self.string_product,      // iProduct
-                                    self.string_serial,       // iSerial
-                                    self.num_configurations,
-                                ];
-
-                                // requested len too short: wLength < real length
-                                if setup_packet.length < desc.len() as u16 {
-                                    desc.resize(setup_packet.length as usize, 0);
-                                }
-                                return Ok(desc);
-                            }
-                            Some(BOS) => {
-                                debug!("Get BOS descriptor");
-                                let mut desc = vec![
-                                    0x05,      // bLength
-                                    BOS as u8, // bDescriptorType: BOS
-                                    0x05, 0x00, // wTotalLength
-                                    0x00, // bNumCapabilities
-                                ];
-
-                                // requested len too short: wLength < real length
-                                if setup_packet.length < desc.len() as u16 {
-                                    desc.resize(setup_packet.length as usize, 0);
-                                }
-                                return Ok(desc);
-                            }
-                            Some(Configuration) => {
-                                debug!("Get configuration descriptor");
-                                let mut desc = vec![
-                                    0x09,                // bLength
-                                    Configuration as u8, // bDescriptorType: Configuration
-                                    0x00,
-                                    0x00, // wTotalLength: to be filled below
-                                    self.interfaces.len() as u8, // bNumInterfaces
-                                    self.configuration_value, // bConfigurationValue
-                                    self.string_configuration, // iConfiguration
-                                    0x80, // bmAttributes Bus Powered
-                                    0x32, // bMaxPower 100mA
-                                ];
-                                for (i, intf) in self.interfaces.iter().enumerate() {
-                                    let mut intf_desc = vec![
-                                        0x09,                       // bLength
-                                        Interface as u8,            // bDescriptorType: Interface
-                                        i as u8,                    // bInterfaceNum
-                                        0x00,                       // bAlternateSettings
-                                        intf.endpoints.len() as u8, // bNumEndpoints
-                                        intf.interface_class,       // bInterfaceClass
-                                        intf.interface_subclass,    // bInterfaceSubClass
-                                        intf.interface_protocol,    // bInterfaceProtocol
-                                        intf.string_interface,      //iInterface
-                                    ];
-                                    // class specific endpoint
-                                    let mut specific = intf.class_specific_descriptor.clone();
-                                    intf_desc.append(&mut specific);
-                                    // endpoint descriptors
-                                    for endpoint in &intf.endpoints {
-                                        let mut ep_desc = vec![
-                                            0x07,                // bLength
-                                            Endpoint as u8,      // bDescriptorType: Endpoint
-                                            endpoint.address,    // bEndpointAddress
-                                            endpoint.attributes, // bmAttributes
-                                            endpoint.max_packet_size as u8,
-                                            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
-                                            endpoint.interval,                     // bInterval
-                                        ];
-                                        intf_desc.append(&mut ep_desc);
-                                    }
-                                    desc.append(&mut intf_desc);
-                                }
-                                // length
-                                let len = desc.len() as u16;
-                                desc[2] = len as u8;
-                                desc[3] = (len >> 8) as u8;
-
-                                // requested len too short: wLength < real length
-                                if setup_packet.length < desc.len() as u16 {
-                                    desc.resize(setup_packet.length as usize, 0);
-                                }
-                                return Ok(desc);
-                            }
-                            Some(String) => {
-                                debug!("Get string descriptor");
-                                let index = setup_packet.value as u8;
-                                if index == 0 {
-                                    // language ids
-                                    let mut desc = vec![
-                                        4,                            // bLength
-                                        DescriptorType::String as u8, // bDescriptorType
-                                        0x09,
-                                        0x04, // bLANGID, en-US
-                                    ];
-                                    // requested len too short: wLength < real length
-                                    if setup_packet.length < desc.len() as u16 {
-                                        desc.resize(setup_packet.length as usize, 0);
-                                    }
-                                    return Ok(desc);
-                                } else {
-                                    let s = &self.string_pool[&index];
-                                    let bytes: Vec<u16> = s.encode_utf16().collect();
-                                    let mut desc = vec![
-                                        (2 + bytes.len() * 2) as u8,  // bLength
-                                        DescriptorType::String as u8, // bDescriptorType
-                                    ];
-                                    for byte in bytes {
-                                        desc.push(byte as u8);
-                                        desc.push((byte >> 8) as u8);
-                                    }
+        let mut resp = match (
+            setup_packet.request_type,
+            FromPrimitive::from_u8(setup_packet.request),
+        ) {
+            (0b10000000, Some(GetDescriptor)) => self.get_descriptor(setup_packet),
+            (0b10000000, Some(GetConfiguration)) => Ok(vec![self.configuration_value()]),
+            (0b00000000, Some(SetConfiguration)) => {
+                self.set_configuration(setup_packet.value as u8)?;
+                Ok(vec![])
+            }
+            (0b10000001, Some(GetInterface)) => {
+                let intf = self
+                    .interfaces()
+                    .get(setup_packet.index as usize & 0xFF)
+                    .ok_or(UrbError::Stall)?;
+                Ok(vec![intf.alternate_setting()])
+            }
+            (0b00000001, Some(SetInterface)) => {
+                let number = setup_packet.index as u8;
+                let intf = self
+                    .interfaces()
+                    .get(number as usize)
+                    .ok_or(UrbError::Stall)?;
+                intf.set_alternate_setting(number, setup_packet.value as u8)?;
+                Ok(vec![])
+            }
+            _ if setup_packet.request_type & 0xF == 1 => {
+                // to interface
+                // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
+                // only low 8 bits are valid
+                let intf = self
+                    .interfaces()
+                    .get(setup_packet.index as usize & 0xFF)
+                    .ok_or(UrbError::Stall)?;
+                let mut handler = intf.handler.lock().unwrap();
+                handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+            }
+            _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
+                // to device
+                // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
+                let lock = self.device_handler.as_ref().unwrap();
+                let mut handler = lock.lock().unwrap();
+                handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+            }
+            _ => {
+                warn!("unhandled control {:?}: {:x?}", direction, setup_packet);
+                Err(UrbError::Stall)
+            }
+        }?;
+        match direction {
+            // requested len too short: wLength < real length
+            Direction::In => resp.truncate(setup_packet.length as usize),
+            // nothing is returned for OUT
+            Direction::Out => resp.clear(),
+        }
+        Ok(resp)
+    }
 
-                                    // requested len too short: wLength < real length
-                                    if setup_packet.length < desc.len() as u16 {
-                                        desc.resize(setup_packet.length as usize, 0);
-                                    }
-                                    return Ok(desc);
-                                }
-                            }
-                            Some(DeviceQualifier) => {
-                                debug!("Get device qualifier descriptor");
-                                let mut desc = vec![
-                                    0x0A,                  // bLength
-                                    DeviceQualifier as u8, // bDescriptorType: Device Qualifier
-                                    self.usb_version.minor as u8,
-                                    self.usb_version.major as u8,
-                                    self.device_class,    // bDeviceClass
-                                    self.device_subclass, // bDeviceSUbClass
-                                    self.device_protocol, // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.num_configurations, // bNumConfigurations
-                                    0x00,                 // reserved
-                                ];
+    /// Handle GET_DESCRIPTOR to the device, the descriptor is truncated to wLength by the caller
+    fn get_descriptor(&self, setup_packet: SetupPacket) -> UrbResult<Vec<u8>> {
+        use DescriptorType::*;
 
-                                // requested len too short: wLength < real length
-                                if setup_packet.length < desc.len() as u16 {
-                                    desc.resize(setup_packet.length as usize, 0);
-                                }
-                                return Ok(desc);
-                            }
-                            _ => {
-                                warn!("unknown desc type: {:x?}", setup_packet);
-                                return Ok(vec![]);
-                            }
-                        }
-                    }
-                    _ if setup_packet.request_type & 0xF == 1 => {
-                        // to interface
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        // only low 8 bits are valid
-                        let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        let resp = handler.handle_urb(intf, ep, setup_packet, &out_data)?;
-                        return Ok(resp);
-                    }
-                    _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
-                        // to device
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        let lock = self.device_handler.as_ref().unwrap();
-                        let mut handler = lock.lock().unwrap();
-                        return Ok(handler.handle_urb(setup_packet, &out_data)?);
+        match FromPrimitive::from_u16(setup_packet.value >> 8) {
+            Some(Device) => {
+                debug!("Get device descriptor");
+                let desc = vec![
+                    0x12,         // bLength
+                    Device as u8, // bDescriptorType: Device
+                    self.usb_version.minor,
+                    self.usb_version.major,            // bcdUSB: USB 2.0
+                    self.device_class,                 // bDeviceClass
+                    self.device_subclass,              // bDeviceSubClass
+                    self.device_protocol,              // bDeviceProtocol
+                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
+                    self.vendor_id as u8,              // idVendor
+                    (self.vendor_id >> 8) as u8,
+                    self.product_id as u8, // idProduct
+                    (self.product_id >> 8) as u8,
+                    self.device_bcd.minor, // bcdDevice
+                    self.device_bcd.major,
+                    self.string_manufacturer,        // iManufacturer
+                    self.string_product,             // iProduct
+                    self.string_serial,              // iSerial
+                    self.configurations.len() as u8, // bNumConfigurations
+                ];
+                Ok(desc)
+            }
+            Some(BOS) => {
+                debug!("Get BOS descriptor");
+                let desc = vec![
+                    0x05,      // bLength
+                    BOS as u8, // bDescriptorType: BOS
+                    0x05, 0x00, // wTotalLength
+                    0x00, // bNumCapabilities
+                ];
+                Ok(desc)
+            }
+            Some(Configuration) => {
+                debug!("Get configuration descriptor");
+                // low byte: index
+                let configuration = self
+                    .configurations
+                    .get(setup_packet.value as usize & 0xFF)
+                    .ok_or(UrbError::Stall)?;
+                Ok(configuration.descriptor())
+            }
+            Some(String) => {
+                debug!("Get string descriptor");
+                let index = setup_packet.value as u8;
+                if index == 0 {
+                    // language ids
+                    let desc = vec![
+                        4,                            // bLength
+                        DescriptorType::String as u8, // bDescriptorType
+                        0x09,
+                        0x04, // bLANGID, en-US
+                    ];
+                    Ok(desc)
+                } else {
+                    let Some(s) = self.string_pool.get(&index) else {
+                        warn!("unknown string descriptor: {}", index);
+                        return Err(UrbError::Stall);
+                    };
+                    let bytes: Vec<u16> = s.encode_utf16().collect();
+                    let mut desc = vec![
+                        (2 + bytes.len() * 2) as u8,  // bLength
+                        DescriptorType::String as u8, // bDescriptorType
+                    ];
+                    for byte in bytes {
+                        desc.push(byte as u8);
+                        desc.push((byte >> 8) as u8);
                     }
-                    _ => unimplemented!("control in"),
+                    Ok(desc)
                 }
             }
-            (Some(Control), Out) => {
-                // control out
-                debug!("Control OUT setup={:x?}", setup_packet);
-                match (
-                    setup_packet.request_type,
-                    FromPrimitive::from_u8(setup_packet.request),
-                ) {
-                    (0b00000000, Some(SetConfiguration)) => {
-                        let mut desc = vec![
-                            self.configuration_value, // bConfigurationValue
-                        ];
+            Some(DeviceQualifier) => {
+                debug!("Get device qualifier descriptor");
+                let desc = vec![
+                    0x0A,                  // bLength
+                    DeviceQualifier as u8, // bDescriptorType: Device Qualifier
+                    self.usb_version.minor,
+                    self.usb_version.major,
+                    self.device_class,                 // bDeviceClass
+                    self.device_subclass,              // bDeviceSUbClass
+                    self.device_protocol,              // bDeviceProtocol
+                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
+                    self.configurations.len() as u8,   // bNumConfigurations
+                    0x00,                              // reserved
+                ];
+                Ok(desc)
+            }
+            _ => {
+                warn!("unknown desc type: {:x?}", setup_packet);
+                Err(UrbError::Stall)
+            }
+        }
+    }
 
-                        // requested len too short: wLength < real length
-                        if setup_packet.length < desc.len() as u16 {
-                            desc.resize(setup_packet.length as usize, 0);
+    /// Handle an isochronous URB packet by packet
+    ///
+    /// Return the IN data of all packets packed together, and the descriptors with actual lengths and statuses
+    pub(crate) fn handle_iso_urb(
+        &self,
+        ep: UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        packets: &[IsoPacketDescriptor],
+        out_data: &[u8],
+    ) -> UrbResult<(Vec<u8>, Vec<IsoPacketDescriptor>)> {
+        let intf = intf.ok_or(UrbError::Stall)?;
+        let mut handler = intf.handler.lock().unwrap();
+        let mut data = vec![];
+        let mut result = vec![];
+        for packet in packets {
+            let mut packet = *packet;
+            let req = match ep.direction() {
+                Direction::Out => {
+                    let start = packet.offset as usize;
+                    match out_data.get(start..start + packet.length as usize) {
+                        Some(req) => req,
+                        None => {
+                            // packet out of the transfer buffer
+                            packet.actual_length = 0;
+                            packet.status = -EPROTO;
+                            result.push(packet);
+                            continue;
                         }
-                        return Ok(desc);
-                    }
-                    _ if setup_packet.request_type & 0xF == 1 => {
-                        // to interface
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        // only low 8 bits are valid
-                        let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        let resp = handler.handle_urb(intf, ep, setup_packet, &out_data)?;
-                        return Ok(resp);
                     }
-                    _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
-                        // to device
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        let lock = self.device_handler.as_ref().unwrap();
-                        let mut handler = lock.lock().unwrap();
-                        return Ok(handler.handle_urb(setup_packet, &out_data)?);
+                }
+                Direction::In => &[],
+            };
+            match handler.handle_iso_packet(intf, ep, packet.length, req) {
+                Ok(mut resp) => {
+                    packet.status = 0;
+                    if let Direction::In = ep.direction() {
+                        if resp.len() > packet.length as usize {
+                            resp.truncate(packet.length as usize);
+                            packet.status = -EOVERFLOW;
+                        }
+                        packet.actual_length = resp.len() as u32;
+                        data.extend_from_slice(&resp);
+                    } else {
+                        packet.actual_length = packet.length;
                     }
-                    _ => unimplemented!("control out"),
+                }
+                Err(UrbError::Io(err)) => return Err(UrbError::Io(err)),
+                Err(err) => {
+                    packet.actual_length = 0;
+                    packet.status = -err.errno().unwrap();
                 }
             }
-            (Some(_), _) => {
-                // others
-                let intf = intf.unwrap();
-                let mut handler = intf.handler.lock().unwrap();
-                let resp = handler.handle_urb(intf, ep, setup_packet, &out_data)?;
-                return Ok(resp);
-            }
-            _ => unimplemented!("transfer to {:?}", ep),
+            result.push(packet);
         }
+        Ok((data, result))
     }
 }
 
@@ -453,8 +571,25 @@ impl UsbDevice {
 pub trait UsbDeviceHandler {
     /// Handle a URB(USB Request Block) targeting at this device
     ///
-    /// When the lower 4 bits of bmRequestType is zero and the URB is not handled by the library, this function is called
-    fn handle_urb(&mut self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>>;
+    /// When the lower 4 bits of bmRequestType is zero and the URB is not handled by the library, this function is called.
+    /// For IN, return at most `transfer_buffer_length` bytes.
+    /// USBIP_CMD_UNLINK does not interrupt a running call, its result is discarded.
+    ///
+    /// Return [UrbError::Stall] for unsupported requests
+    fn handle_urb(
+        &mut self,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>>;
+
+    /// Handle SET_CONFIGURATION to `configuration_value`, zero to unconfigure the device
+    ///
+    /// Called before the configuration changes, an error fails the request. Accepted by default
+    fn set_configuration(&mut self, configuration_value: u8) -> UrbResult<()> {
+        let _ = configuration_value;
+        Ok(())
+    }
 
     /// Helper to downcast to actual struct
     ///
@@ -89,9 +89,10 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
         setup: SetupPacket,
         _req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult<Vec<u8>> {
         use StandardRequest::*;
         if ep.is_ep0() {
             // control transfers
@@ -102,10 +103,16 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                         Some(HidDescriptorType::Report) => {
                             return Ok(self.report_descriptor.clone());
                         }
-                        _ => unimplemented!("hid descriptor {:?}", setup),
+                        _ => {
+                            warn!("unknown hid descriptor {:?}", setup);
+                            return Err(UrbError::Stall);
+                        }
                     }
                 }
-                _ => unimplemented!("hid request {:?}", setup),
+                _ => {
+                    warn!("unknown hid request {:?}", setup);
+                    return Err(UrbError::Stall);
+                }
             }
         } else {
             // interrupt transfer
@@ -134,7 +141,7 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
     }
 
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        return vec![
+        vec![
             0x09,                         // bLength
             HidDescriptorType::Hid as u8, // bDescriptorType: HID
             0x11,
@@ -144,7 +151,7 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
             HidDescriptorType::Report as u8, // bDescriptorType[0] HID
             self.report_descriptor.len() as u8,
             (self.report_descriptor.len() >> 8) as u8, // wDescriptorLength[0]
-        ];
+        ]
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -1,15 +1,88 @@
 //! Host USB
 use super::*;
+use std::collections::BTreeSet;
+
+/// Length of the data stage of a control transfer
+///
+/// libusb sends the length of the buffer as wLength, so it must not exceed the one of the request.
+fn control_length(setup: SetupPacket, transfer_buffer_length: u32) -> usize {
+    transfer_buffer_length.min(setup.length as u32) as usize
+}
+
+/// Interfaces of a device of the host claimed by its handlers
+///
+/// Kernel drivers are detached on claim. All claims are released, and kernel drivers reattached, when the
+/// configuration changes and once the last handler sharing them is dropped.
+#[derive(Clone)]
+pub struct UsbHostClaims {
+    inner: Arc<Mutex<ClaimedInterfaces>>,
+}
+
+struct ClaimedInterfaces {
+    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+    numbers: BTreeSet<u8>,
+}
+
+impl ClaimedInterfaces {
+    fn release_all(&mut self) {
+        let handle = self.handle.lock().unwrap();
+        for number in std::mem::take(&mut self.numbers) {
+            if let Err(err) = handle.release_interface(number) {
+                warn!("Failed to release interface {}: {}", number, err);
+            }
+        }
+    }
+}
+
+impl Drop for ClaimedInterfaces {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+impl UsbHostClaims {
+    /// No claimed interfaces of the device opened as `handle`, detaching kernel drivers on claim
+    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
+        handle
+            .lock()
+            .unwrap()
+            .set_auto_detach_kernel_driver(true)
+            .ok();
+        Self {
+            inner: Arc::new(Mutex::new(ClaimedInterfaces {
+                handle,
+                numbers: BTreeSet::new(),
+            })),
+        }
+    }
+
+    /// Claim interface `number` unless it is claimed already
+    fn claim(&self, number: u8) -> rusb::Result<()> {
+        let mut claimed = self.inner.lock().unwrap();
+        if !claimed.numbers.contains(&number) {
+            claimed.handle.lock().unwrap().claim_interface(number)?;
+            claimed.numbers.insert(number);
+        }
+        Ok(())
+    }
+
+    fn release_all(&self) {
+        self.inner.lock().unwrap().release_all();
+    }
+}
 
 /// A handler to pass requests to a USB device of the host
 #[derive(Clone)]
 pub struct UsbHostInterfaceHandler {
     handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+    claims: UsbHostClaims,
 }
 
 impl UsbHostInterfaceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+    /// Pass requests to an interface of `handle`, tracking its claim in `claims` shared with the
+    /// other handlers of the device
+    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>, claims: UsbHostClaims) -> Self {
+        Self { handle, claims }
     }
 }
 
@@ -18,72 +91,105 @@ impl UsbInterfaceHandler for UsbHostInterfaceHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
+        transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> UrbResult<Vec<u8>> {
         debug!(
             "To host device: ep={:?} setup={:?} req={:?}",
             ep, setup, req
         );
-        let mut buffer = vec![0u8; ep.max_packet_size as usize];
+        let length = if ep.attributes == EndpointAttributes::Control as u8 {
+            control_length(setup, transfer_buffer_length)
+        } else {
+            transfer_buffer_length as usize
+        };
+        let mut buffer = vec![0u8; length];
         let timeout = std::time::Duration::new(1, 0);
         let handle = self.handle.lock().unwrap();
         if ep.attributes == EndpointAttributes::Control as u8 {
             // control
             if let Direction::In = ep.direction() {
                 // control in
-                if let Ok(len) = handle.read_control(
+                let len = handle.read_control(
                     setup.request_type,
                     setup.request,
                     setup.value,
                     setup.index,
                     &mut buffer,
                     timeout,
-                ) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                )?;
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // control out
-                handle
-                    .write_control(
-                        setup.request_type,
-                        setup.request,
-                        setup.value,
-                        setup.index,
-                        req,
-                        timeout,
-                    )
-                    .ok();
+                handle.write_control(
+                    setup.request_type,
+                    setup.request,
+                    setup.value,
+                    setup.index,
+                    req,
+                    timeout,
+                )?;
             }
         } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
             // interrupt
             if let Direction::In = ep.direction() {
                 // interrupt in
-                if let Ok(len) = handle.read_interrupt(ep.address, &mut buffer, timeout) {
-                    info!("intr in {:?}", &buffer[..len]);
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len = handle.read_interrupt(ep.address, &mut buffer, timeout)?;
+                trace!("intr in {:?}", &buffer[..len]);
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // interrupt out
-                handle.write_interrupt(ep.address, req, timeout).ok();
+                handle.write_interrupt(ep.address, req, timeout)?;
             }
         } else if ep.attributes == EndpointAttributes::Bulk as u8 {
             // bulk
             if let Direction::In = ep.direction() {
                 // bulk in
-                if let Ok(len) = handle.read_bulk(ep.address, &mut buffer, timeout) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len = handle.read_bulk(ep.address, &mut buffer, timeout)?;
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // bulk out
-                handle.write_bulk(ep.address, req, timeout).ok();
+                handle.write_bulk(ep.address, req, timeout)?;
             }
         }
         Ok(vec![])
     }
 
+    fn handle_zero_length_packet(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+    ) -> UrbResult<()> {
+        let timeout = std::time::Duration::new(1, 0);
+        let handle = self.handle.lock().unwrap();
+        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+            handle.write_interrupt(ep.address, &[], timeout)?;
+        } else {
+            handle.write_bulk(ep.address, &[], timeout)?;
+        }
+        Ok(())
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        return vec![];
+        vec![]
+    }
+
+    fn set_alternate_setting(
+        &mut self,
+        _interface: &UsbInterface,
+        number: u8,
+        alternate_setting: u8,
+    ) -> UrbResult<()> {
+        debug!(
+            "To host device: set interface {} alternate setting {}",
+            number, alternate_setting
+        );
+        // libusb only selects alternate settings of claimed interfaces
+        self.claims.claim(number)?;
+        let handle = self.handle.lock().unwrap();
+        handle.set_alternate_setting(number, alternate_setting)?;
+        Ok(())
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -95,50 +201,87 @@ impl UsbInterfaceHandler for UsbHostInterfaceHandler {
 #[derive(Clone)]
 pub struct UsbHostDeviceHandler {
     handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+    claims: UsbHostClaims,
 }
 
 impl UsbHostDeviceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+    /// Pass requests to the device `handle`, releasing the interfaces in `claims` before changing
+    /// the configuration
+    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>, claims: UsbHostClaims) -> Self {
+        Self { handle, claims }
     }
 }
 
 impl UsbDeviceHandler for UsbHostDeviceHandler {
-    fn handle_urb(&mut self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>> {
+    fn handle_urb(
+        &mut self,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
         debug!("To host device: setup={:?} req={:?}", setup, req);
-        let mut buffer = [0u8; 1024];
+        let mut buffer = vec![0u8; control_length(setup, transfer_buffer_length)];
         let timeout = std::time::Duration::new(1, 0);
         let handle = self.handle.lock().unwrap();
         // control
         if setup.request_type & 0x80 == 0 {
             // control out
-            handle
-                .write_control(
-                    setup.request_type,
-                    setup.request,
-                    setup.value,
-                    setup.index,
-                    req,
-                    timeout,
-                )
-                .ok();
+            handle.write_control(
+                setup.request_type,
+                setup.request,
+                setup.value,
+                setup.index,
+                req,
+                timeout,
+            )?;
         } else {
             // control in
-            if let Ok(len) = handle.read_control(
+            let len = handle.read_control(
                 setup.request_type,
                 setup.request,
                 setup.value,
                 setup.index,
                 &mut buffer,
                 timeout,
-            ) {
-                return Ok(Vec::from(&buffer[..len]));
-            }
+            )?;
+            return Ok(Vec::from(&buffer[..len]));
         }
         Ok(vec![])
     }
 
+    fn set_configuration(&mut self, configuration_value: u8) -> UrbResult<()> {
+        debug!("To host device: set configuration {}", configuration_value);
+        // libusb cannot change the configuration while interfaces are claimed
+        self.claims.release_all();
+        let handle = self.handle.lock().unwrap();
+        if configuration_value == 0 {
+            // zero is a valid configuration for libusb
+            handle.unconfigure()?;
+        } else {
+            handle.set_active_configuration(configuration_value)?;
+        }
+        Ok(())
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_length_of_request() {
+        let setup = SetupPacket {
+            request_type: 0x80,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Device as u16) << 8,
+            index: 0,
+            length: 0x12,
+        };
+        assert_eq!(control_length(setup, 0x40), 0x12);
+        assert_eq!(control_length(setup, 0x08), 0x08);
+    }
+}
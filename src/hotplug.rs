@@ -0,0 +1,212 @@
+//! Hot-plug of host devices
+use super::*;
+use rusb::{Hotplug, HotplugBuilder};
+
+/// A device plugged into or unplugged from the host
+#[derive(Debug)]
+pub enum HotplugEvent<D> {
+    Arrived(D),
+    Left(D),
+}
+
+/// A device of the host that can be shared
+pub trait HostDevice: Send + 'static {
+    /// Bus id of the device when exported, stable until it is unplugged
+    fn bus_id(&self) -> String;
+
+    /// Open the device to share it, `None` if it cannot be opened
+    fn share(self) -> Option<UsbDevice>;
+}
+
+impl HostDevice for Device<GlobalContext> {
+    fn bus_id(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            self.bus_number(),
+            self.address(),
+            self.port_number()
+        )
+    }
+
+    fn share(self) -> Option<UsbDevice> {
+        UsbIpServer::from_host_device(self)
+    }
+}
+
+/// Source of hot-plug events, [LibusbHotplug] for devices of the host
+pub trait HotplugSource<D: HostDevice>: Send + 'static {
+    /// Send events to `events` until it is closed
+    ///
+    /// Devices present when called are reported as arrived
+    fn start(self, events: mpsc::UnboundedSender<HotplugEvent<D>>) -> Result<()>;
+}
+
+/// Hot-plug events of host devices from libusb
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LibusbHotplug;
+
+struct LibusbHotplugCallback {
+    events: mpsc::UnboundedSender<HotplugEvent<Device<GlobalContext>>>,
+}
+
+impl Hotplug<GlobalContext> for LibusbHotplugCallback {
+    fn device_arrived(&mut self, device: Device<GlobalContext>) {
+        // opening the device in the callback is not allowed, so defer it
+        self.events.send(HotplugEvent::Arrived(device)).ok();
+    }
+
+    fn device_left(&mut self, device: Device<GlobalContext>) {
+        self.events.send(HotplugEvent::Left(device)).ok();
+    }
+}
+
+impl HotplugSource<Device<GlobalContext>> for LibusbHotplug {
+    fn start(
+        self,
+        events: mpsc::UnboundedSender<HotplugEvent<Device<GlobalContext>>>,
+    ) -> Result<()> {
+        if !rusb::has_hotplug() {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "libusb does not support hot-plug on this platform",
+            ));
+        }
+        let context = GlobalContext::default();
+        let registration = HotplugBuilder::new()
+            .enumerate(true)
+            .register(
+                context,
+                Box::new(LibusbHotplugCallback {
+                    events: events.clone(),
+                }),
+            )
+            .map_err(std::io::Error::other)?;
+        std::thread::spawn(move || {
+            // callbacks are called while handling events
+            while !events.is_closed() {
+                if let Err(err) = context.handle_events(Some(std::time::Duration::from_secs(1))) {
+                    warn!("Failed to handle libusb events: {}", err);
+                    break;
+                }
+            }
+            drop(registration);
+        });
+        Ok(())
+    }
+}
+
+impl UsbIpServer {
+    /// Export devices from `source` as they arrive and remove them when they leave
+    ///
+    /// Only devices matching `filter` are exported, as in [UsbIpServer::new_from_host_with_filter]
+    pub fn watch_hotplug<D, S, F>(self: &Arc<Self>, source: S, mut filter: F) -> Result<()>
+    where
+        D: HostDevice,
+        S: HotplugSource<D>,
+        F: FnMut(&D) -> bool + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        source.start(tx)?;
+        // stop when the server is dropped
+        let server = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let Some(server) = server.upgrade() else {
+                    break;
+                };
+                match event {
+                    HotplugEvent::Arrived(device) => {
+                        let bus_id = device.bus_id();
+                        if !filter(&device) || server.has_device(&bus_id) {
+                            continue;
+                        }
+                        // opening reads descriptors from the device
+                        match tokio::task::spawn_blocking(move || device.share()).await {
+                            Ok(Some(device)) => server.add_device(device),
+                            _ => warn!("Impossible to share hot-plugged device {}", bus_id),
+                        }
+                    }
+                    HotplugEvent::Left(device) => {
+                        server.remove_device(&device.bus_id());
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Export devices of the host matching `filter` as they are plugged in, using libusb hot-plug
+    ///
+    /// Devices already plugged in are exported too
+    pub fn watch_host_hotplug<F>(self: &Arc<Self>, filter: F) -> Result<()>
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool + Send + 'static,
+    {
+        self.watch_hotplug(LibusbHotplug, filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockHostDevice {
+        bus_id: String,
+    }
+
+    impl HostDevice for MockHostDevice {
+        fn bus_id(&self) -> String {
+            self.bus_id.clone()
+        }
+
+        fn share(self) -> Option<UsbDevice> {
+            let mut device = UsbDevice::new(0);
+            device.bus_id = self.bus_id;
+            Some(device)
+        }
+    }
+
+    struct MockHotplugSource {
+        events: Vec<HotplugEvent<MockHostDevice>>,
+    }
+
+    impl HotplugSource<MockHostDevice> for MockHotplugSource {
+        fn start(self, events: mpsc::UnboundedSender<HotplugEvent<MockHostDevice>>) -> Result<()> {
+            for event in self.events {
+                events.send(event).ok();
+            }
+            Ok(())
+        }
+    }
+
+    fn mock_device(bus_id: &str) -> MockHostDevice {
+        MockHostDevice {
+            bus_id: bus_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn hotplug_filter() {
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let source = MockHotplugSource {
+            events: vec![
+                HotplugEvent::Arrived(mock_device("1-1")),
+                HotplugEvent::Arrived(mock_device("1-2")),
+                HotplugEvent::Arrived(mock_device("2-1")),
+                HotplugEvent::Left(mock_device("1-1")),
+            ],
+        };
+        server
+            .watch_hotplug(source, |dev: &MockHostDevice| dev.bus_id.starts_with("1-"))
+            .unwrap();
+        for _ in 0..100 {
+            if !server.has_device("1-1") && server.has_device("1-2") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!server.has_device("1-1"));
+        assert!(server.has_device("1-2"));
+        assert!(!server.has_device("2-1"));
+    }
+}
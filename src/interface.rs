@@ -1,15 +1,76 @@
 use super::*;
 
-/// Represent a USB interface
-#[derive(Clone)]
-pub struct UsbInterface {
+/// Represent an alternate setting of a USB interface
+#[derive(Clone, Debug, Default)]
+pub struct UsbAlternateSetting {
     pub interface_class: u8,
     pub interface_subclass: u8,
     pub interface_protocol: u8,
     pub endpoints: Vec<UsbEndpoint>,
     pub string_interface: u8,
     pub class_specific_descriptor: Vec<u8>,
+}
+
+/// Represent a USB interface
+#[derive(Clone)]
+pub struct UsbInterface {
+    /// Alternate settings by bAlternateSetting, at least one
+    pub alternate_settings: Vec<UsbAlternateSetting>,
     pub handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    /// bAlternateSetting of the active setting
+    pub(crate) active_setting: Arc<Mutex<u8>>,
+}
+
+impl UsbInterface {
+    /// An interface with its handler, `alternate_settings` are numbered from 0
+    pub fn new(
+        alternate_settings: Vec<UsbAlternateSetting>,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    ) -> Self {
+        Self {
+            alternate_settings,
+            handler,
+            active_setting: Arc::default(),
+        }
+    }
+
+    /// bAlternateSetting of the active setting
+    pub fn alternate_setting(&self) -> u8 {
+        *self.active_setting.lock().unwrap()
+    }
+
+    /// The alternate setting selected by SET_INTERFACE
+    pub fn active_setting(&self) -> Option<&UsbAlternateSetting> {
+        self.alternate_settings
+            .get(self.alternate_setting() as usize)
+    }
+
+    /// Endpoints of the active alternate setting
+    pub fn endpoints(&self) -> &[UsbEndpoint] {
+        self.active_setting()
+            .map_or(&[], |setting| &setting.endpoints)
+    }
+
+    /// Switch interface `number` to `alternate_setting` after its handler accepts it
+    pub(crate) fn set_alternate_setting(&self, number: u8, alternate_setting: u8) -> UrbResult<()> {
+        if alternate_setting as usize >= self.alternate_settings.len() {
+            warn!(
+                "unknown alternate setting {} of interface {}",
+                alternate_setting, number
+            );
+            return Err(UrbError::Stall);
+        }
+        self.handler
+            .lock()
+            .unwrap()
+            .set_alternate_setting(self, number, alternate_setting)?;
+        info!(
+            "Set alternate setting of interface {} to {}",
+            number, alternate_setting
+        );
+        *self.active_setting.lock().unwrap() = alternate_setting;
+        Ok(())
+    }
 }
 
 /// A handler of a custom usb interface
@@ -19,14 +80,72 @@ pub trait UsbInterfaceHandler {
 
     /// Handle a URB(USB Request Block) targeting at this interface
     ///
-    /// Can be one of: control transfer to ep0 or other types of transfer to its endpoint
+    /// Can be one of: control transfer to ep0 or other types of transfer to its endpoint.
+    /// For control transfers, `ep` has the direction of the data stage in bmRequestType.
+    /// For IN, return at most `transfer_buffer_length` bytes: excess data is dropped,
+    /// and bulk or interrupt URBs complete with -EOVERFLOW.
+    /// USBIP_CMD_UNLINK does not interrupt a running call, its result is discarded.
+    ///
+    /// Return [UrbError::Stall] for unsupported requests
     fn handle_urb(
         &mut self,
         interface: &UsbInterface,
         ep: UsbEndpoint,
+        transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
-    ) -> Result<Vec<u8>>;
+    ) -> UrbResult<Vec<u8>>;
+
+    /// Handle a packet of an isochronous URB targeting at this interface
+    ///
+    /// Called once for each packet in order. For OUT, `req` is the data of this packet.
+    /// For IN, return at most `length` bytes. An error only fails this packet.
+    ///
+    /// Isochronous transfers are stalled by default
+    fn handle_iso_packet(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        length: u32,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        let _ = (interface, ep, length, req);
+        Err(UrbError::Stall)
+    }
+
+    /// Send a zero length packet to bulk or interrupt OUT endpoint `ep`
+    ///
+    /// Called after [UsbInterfaceHandler::handle_urb] for URBs with URB_ZERO_PACKET whose data
+    /// fills its last packet. Nothing is done by default
+    fn handle_zero_length_packet(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+    ) -> UrbResult<()> {
+        let _ = (interface, ep);
+        Ok(())
+    }
+
+    /// Called when SET_CONFIGURATION selects (`configured`) or deselects the configuration of this interface
+    ///
+    /// Selecting the active configuration again deselects it first.
+    /// A selected interface is in alternate setting 0. Nothing is done by default
+    fn set_configured(&mut self, interface: &UsbInterface, configured: bool) {
+        let _ = (interface, configured);
+    }
+
+    /// Handle SET_INTERFACE of interface `number` to `alternate_setting`
+    ///
+    /// Called before the setting changes, an error fails the request. Accepted by default
+    fn set_alternate_setting(
+        &mut self,
+        interface: &UsbInterface,
+        number: u8,
+        alternate_setting: u8,
+    ) -> UrbResult<()> {
+        let _ = (interface, number, alternate_setting);
+        Ok(())
+    }
 
     /// Helper to downcast to actual struct
     ///
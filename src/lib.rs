@@ -6,169 +6,392 @@ use num_traits::FromPrimitive;
 use rusb::*;
 use std::any::Any;
 use std::collections::{HashMap, VecDeque};
-use std::io::{ErrorKind, Result};
+use std::io::{ErrorKind, IoSlice, Result};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use tokio::io::AsyncReadExt;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, BufReader};
 use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify};
 
+mod access;
+mod capture;
 pub mod cdc;
+mod client;
+mod configuration;
 mod consts;
 mod device;
 mod endpoint;
 pub mod hid;
 mod host;
+mod hotplug;
 mod interface;
+mod limits;
+mod listener;
+mod metrics;
+mod pool;
+pub mod protocol;
+mod record;
 mod setup;
+#[cfg(feature = "tls")]
+mod tls;
+mod urb;
 mod util;
+pub use access::*;
+pub use capture::*;
+pub use client::*;
+pub use configuration::*;
 pub use consts::*;
 pub use device::*;
 pub use endpoint::*;
 pub use host::*;
+pub use hotplug::*;
 pub use interface::*;
+pub use limits::*;
+pub use listener::*;
+pub use metrics::*;
+use pool::*;
+pub use record::*;
 pub use setup::*;
+#[cfg(feature = "tls")]
+pub use tls::*;
+pub use urb::*;
 pub use util::*;
 
+use protocol::*;
+
 /// Main struct of a USB/IP server
+#[derive(Default)]
 pub struct UsbIpServer {
-    devices: Vec<UsbDevice>,
+    devices: Mutex<Vec<UsbDevice>>,
+    /// Devices imported by a connection, by bus id, with the signal that the device is removed
+    imports: Mutex<HashMap<String, Arc<Notify>>>,
+    hide_busy_devices: bool,
+    /// Set to stop accepting and close all connections
+    shutdown: watch::Sender<bool>,
+    access_policy: Option<Box<dyn AccessPolicy>>,
+    max_connections: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    metrics: Arc<Metrics>,
+    limits: ServerLimits,
 }
 
 impl UsbIpServer {
     /// Create a [UsbIpServer] with simulated devices
     pub fn new_simulated(devices: Vec<UsbDevice>) -> Self {
-        Self { devices }
+        Self {
+            devices: Mutex::new(devices),
+            ..Self::default()
+        }
+    }
+
+    /// Omit devices imported by another client from OP_REP_DEVLIST
+    ///
+    /// By default all devices are listed, and importing a busy device fails with [ST_DEV_BUSY]
+    pub fn hide_busy_devices(mut self, hide: bool) -> Self {
+        self.hide_busy_devices = hide;
+        self
+    }
+
+    /// Decide with `policy` which devices each client may list and import
+    ///
+    /// By default all clients have access to all devices
+    pub fn with_access_policy<P: AccessPolicy + 'static>(mut self, policy: P) -> Self {
+        self.access_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Close connections beyond `max` concurrent ones right after accepting them
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Close connections that have not imported a device within `timeout`
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Close connections with an imported device that sent no command for `timeout` and have no pending URBs
+    ///
+    /// The device is released for other clients
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable TCP keepalive on connections accepted by [UsbIpServer::serve], probing after `time` of inactivity
+    pub fn with_tcp_keepalive(mut self, time: Duration) -> Self {
+        self.tcp_keepalive = Some(time);
+        self
+    }
+
+    /// Listen on `addr` and handle connections in a background task
+    ///
+    /// Bind to port 0 to pick a free port, see [UsbIpServerHandle::local_addr]
+    pub async fn serve(self, addr: SocketAddr) -> Result<UsbIpServerHandle> {
+        let listener = TcpListener::bind(addr).await?;
+        match self.tcp_keepalive {
+            Some(time) => self.serve_listener(TcpKeepaliveListener::new(listener, time)),
+            None => self.serve_listener(listener),
+        }
+    }
+
+    /// Handle connections from `listener` in a background task
+    ///
+    /// Use [serve_connection] to handle a single connection instead
+    pub fn serve_listener<L: UsbIpListener>(
+        self,
+        listener: L,
+    ) -> Result<UsbIpServerHandle<L::Addr>> {
+        let local_addr = listener.local_addr()?;
+        let server = Arc::new(self);
+        let task = tokio::spawn(accept_connections(listener, server.clone()));
+        Ok(UsbIpServerHandle {
+            server,
+            local_addr,
+            task,
+        })
+    }
+
+    /// Export `device`, replacing any device with the same bus id
+    pub fn add_device(&self, device: UsbDevice) {
+        self.remove_device(&device.bus_id);
+        info!("Add device {}", device.bus_id);
+        self.devices.lock().unwrap().push(device);
+    }
+
+    /// Stop exporting the device at `bus_id`
+    ///
+    /// An active import of it ends: pending URBs complete with -ENODEV and the connection is closed
+    pub fn remove_device(&self, bus_id: &str) -> Option<UsbDevice> {
+        let mut devices = self.devices.lock().unwrap();
+        let index = devices.iter().position(|dev| dev.bus_id == bus_id)?;
+        let device = devices.remove(index);
+        info!("Remove device {}", bus_id);
+        if let Some(removed) = self.imports.lock().unwrap().remove(bus_id) {
+            removed.notify_one();
+        }
+        Some(device)
+    }
+
+    /// Whether a device at `bus_id` is exported
+    pub fn has_device(&self, bus_id: &str) -> bool {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|dev| dev.bus_id == bus_id)
+    }
+
+    /// Whether the device at `bus_id` is imported by a client
+    pub fn is_busy(&self, bus_id: &str) -> bool {
+        self.imports.lock().unwrap().contains_key(bus_id)
+    }
+
+    /// Whether `device` is listed to the client of `info`
+    fn can_list(&self, info: &ConnectionInfo, device: &UsbDevice) -> bool {
+        let Some(policy) = &self.access_policy else {
+            return true;
+        };
+        let allowed = policy.can_list(info, device);
+        info!(
+            "Access policy {} listing device {} to {:?}",
+            if allowed { "allows" } else { "denies" },
+            device.bus_id,
+            info
+        );
+        allowed
+    }
+
+    /// Whether the client of `info` may import `device`
+    fn can_import(&self, info: &ConnectionInfo, device: &UsbDevice) -> bool {
+        let Some(policy) = &self.access_policy else {
+            return true;
+        };
+        let allowed = policy.can_import(info, device);
+        info!(
+            "Access policy {} importing device {} to {:?}",
+            if allowed { "allows" } else { "denies" },
+            device.bus_id,
+            info
+        );
+        allowed
+    }
+
+    /// Mark the device at `bus_id` busy until the returned import is dropped
+    ///
+    /// Fails with [ST_NODEV], [ST_NA] if denied by the access policy, or [ST_DEV_BUSY]
+    fn try_import(
+        self: &Arc<Self>,
+        bus_id: &str,
+        info: &ConnectionInfo,
+    ) -> std::result::Result<ImportedDevice, u32> {
+        let devices = self.devices.lock().unwrap();
+        let device = devices
+            .iter()
+            .find(|dev| dev.bus_id == bus_id && self.can_list(info, dev))
+            .ok_or(ST_NODEV)?;
+        if !self.can_import(info, device) {
+            return Err(ST_NA);
+        }
+        let removed = match self.imports.lock().unwrap().entry(device.bus_id.clone()) {
+            std::collections::hash_map::Entry::Occupied(_) => return Err(ST_DEV_BUSY),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(Arc::new(Notify::new())).clone()
+            }
+        };
+        Ok(ImportedDevice {
+            server: self.clone(),
+            device: Arc::new(device.clone()),
+            removed,
+        })
     }
 
     fn with_devices(device_list: Vec<Device<GlobalContext>>) -> Vec<UsbDevice> {
-        let mut devices = vec![];
+        device_list
+            .into_iter()
+            .filter_map(Self::from_host_device)
+            .collect()
+    }
 
-        for dev in device_list {
-            let open_device = match dev.open() {
-                Ok(dev) => dev,
-                Err(err) => {
-                    println!("Impossible to share {:?}: {}", dev, err);
-                    continue;
-                }
+    /// Open a device of the host to share it, `None` if it cannot be opened or described
+    fn from_host_device(dev: Device<GlobalContext>) -> Option<UsbDevice> {
+        let open_device = match dev.open() {
+            Ok(dev) => dev,
+            Err(err) => {
+                warn!("Impossible to share {:?}: {}", dev, err);
+                return None;
+            }
+        };
+        let handle = Arc::new(Mutex::new(open_device));
+        let desc = match dev.device_descriptor() {
+            Ok(desc) => desc,
+            Err(err) => {
+                warn!(
+                    "Impossible to read the device descriptor of {:?}: {}",
+                    dev, err
+                );
+                return None;
+            }
+        };
+        // detaches kernel drivers from the interfaces claimed
+        let claims = UsbHostClaims::new(handle.clone());
+        let mut configurations = vec![];
+        for index in 0..desc.num_configurations() {
+            let Ok(cfg) = dev.config_descriptor(index) else {
+                continue;
             };
-            let handle = Arc::new(Mutex::new(open_device));
-            let desc = dev.device_descriptor().unwrap();
-            let cfg = dev.active_config_descriptor().unwrap();
             let mut interfaces = vec![];
-            handle
-                .lock()
-                .unwrap()
-                .set_auto_detach_kernel_driver(true)
-                .ok();
             for intf in cfg.interfaces() {
-                // ignore alternate settings
-                let intf_desc = intf.descriptors().next().unwrap();
-                handle
-                    .lock()
-                    .unwrap()
-                    .set_auto_detach_kernel_driver(true)
-                    .ok();
-                let mut endpoints = vec![];
-
-                for ep_desc in intf_desc.endpoint_descriptors() {
-                    endpoints.push(UsbEndpoint {
-                        address: ep_desc.address(),
-                        attributes: ep_desc.transfer_type() as u8,
-                        max_packet_size: ep_desc.max_packet_size(),
-                        interval: ep_desc.interval(),
+                let mut alternate_settings = vec![];
+                for intf_desc in intf.descriptors() {
+                    let mut endpoints = vec![];
+
+                    for ep_desc in intf_desc.endpoint_descriptors() {
+                        endpoints.push(UsbEndpoint {
+                            address: ep_desc.address(),
+                            attributes: ep_desc.transfer_type() as u8,
+                            max_packet_size: ep_desc.max_packet_size(),
+                            interval: ep_desc.interval(),
+                        });
+                    }
+
+                    alternate_settings.push(UsbAlternateSetting {
+                        interface_class: intf_desc.class_code(),
+                        interface_subclass: intf_desc.sub_class_code(),
+                        interface_protocol: intf_desc.protocol_code(),
+                        endpoints,
+                        string_interface: intf_desc.description_string_index().unwrap_or(0),
+                        class_specific_descriptor: Vec::from(intf_desc.extra()),
                     });
                 }
 
                 let handler = Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
                     handle.clone(),
+                    claims.clone(),
                 ))
                     as Box<dyn UsbInterfaceHandler + Send>));
-                interfaces.push(UsbInterface {
-                    interface_class: intf_desc.class_code(),
-                    interface_subclass: intf_desc.sub_class_code(),
-                    interface_protocol: intf_desc.protocol_code(),
-                    endpoints,
-                    string_interface: intf_desc.description_string_index().unwrap_or(0),
-                    class_specific_descriptor: Vec::from(intf_desc.extra()),
-                    handler,
-                });
+                interfaces.push(UsbInterface::new(alternate_settings, handler));
+            }
+            let mut attributes = CONFIGURATION_BUS_POWERED;
+            if cfg.self_powered() {
+                attributes |= CONFIGURATION_SELF_POWERED;
+            }
+            if cfg.remote_wakeup() {
+                attributes |= CONFIGURATION_REMOTE_WAKEUP;
             }
-            let mut device = UsbDevice {
-                path: format!(
-                    "/sys/bus/{}/{}/{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
-                bus_id: format!(
-                    "{}-{}-{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
-                bus_num: dev.bus_number() as u32,
-                dev_num: dev.port_number() as u32,
-                speed: dev.speed() as u32,
-                vendor_id: desc.vendor_id(),
-                product_id: desc.product_id(),
-                device_class: desc.class_code(),
-                device_subclass: desc.sub_class_code(),
-                device_protocol: desc.protocol_code(),
-                device_bcd: desc.device_version().into(),
+            configurations.push(UsbConfiguration {
                 configuration_value: cfg.number(),
-                num_configurations: desc.num_configurations(),
-                ep0_in: UsbEndpoint {
-                    address: 0x80,
-                    attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: desc.max_packet_size() as u16,
-                    interval: 0,
-                },
-                ep0_out: UsbEndpoint {
-                    address: 0x00,
-                    attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: desc.max_packet_size() as u16,
-                    interval: 0,
-                },
+                attributes,
+                max_power: (cfg.max_power() / 2) as u8,
+                string_configuration: cfg.description_string_index().unwrap_or(0),
                 interfaces,
-                device_handler: Some(Arc::new(Mutex::new(Box::new(UsbHostDeviceHandler::new(
-                    handle.clone(),
-                ))))),
-                usb_version: desc.usb_version().into(),
-                ..UsbDevice::default()
-            };
+            });
+        }
+        // unconfigured if there is no active configuration
+        let active_configuration = dev.active_config_descriptor().map_or(0, |cfg| cfg.number());
+        let mut device = UsbDevice {
+            path: format!(
+                "/sys/bus/{}/{}/{}",
+                dev.bus_number(),
+                dev.address(),
+                dev.port_number()
+            ),
+            bus_id: dev.bus_id(),
+            bus_num: dev.bus_number() as u32,
+            dev_num: dev.port_number() as u32,
+            speed: dev.speed() as u32,
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            device_class: desc.class_code(),
+            device_subclass: desc.sub_class_code(),
+            device_protocol: desc.protocol_code(),
+            device_bcd: desc.device_version().into(),
+            configurations,
+            ep0_in: UsbEndpoint {
+                address: 0x80,
+                attributes: EndpointAttributes::Control as u8,
+                max_packet_size: desc.max_packet_size() as u16,
+                interval: 0,
+            },
+            ep0_out: UsbEndpoint {
+                address: 0x00,
+                attributes: EndpointAttributes::Control as u8,
+                max_packet_size: desc.max_packet_size() as u16,
+                interval: 0,
+            },
+            device_handler: Some(Arc::new(Mutex::new(Box::new(UsbHostDeviceHandler::new(
+                handle.clone(),
+                claims,
+            ))))),
+            usb_version: desc.usb_version().into(),
+            active_configuration: Arc::new(Mutex::new(active_configuration)),
+            ..UsbDevice::default()
+        };
 
-            // set strings
-            if let Some(index) = desc.manufacturer_string_index() {
-                device.string_manufacturer = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
+        // set strings, left out if unreadable
+        let read_string = |index| match handle.lock().unwrap().read_string_descriptor_ascii(index) {
+            Ok(string) => Some(string),
+            Err(err) => {
+                warn!("Impossible to read string {} of {:?}: {}", index, dev, err);
+                None
             }
-            if let Some(index) = desc.product_string_index() {
-                device.string_product = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
-            }
-            if let Some(index) = desc.serial_number_string_index() {
-                device.string_serial = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
-            }
-            devices.push(device);
+        };
+        if let Some(string) = desc.manufacturer_string_index().and_then(read_string) {
+            device.string_manufacturer = device.new_string(&string)
+        }
+        if let Some(string) = desc.product_string_index().and_then(read_string) {
+            device.string_product = device.new_string(&string)
         }
-        devices
+        if let Some(string) = desc.serial_number_string_index().and_then(read_string) {
+            device.string_serial = device.new_string(&string)
+        }
+        Some(device)
     }
 
     /// Create a [UsbIpServer] exposing devices in the host, and redirect all USB transfers to them using libusb
@@ -180,10 +403,11 @@ impl UsbIpServer {
                     devs.push(d)
                 }
                 Self {
-                    devices: Self::with_devices(devs),
+                    devices: Mutex::new(Self::with_devices(devs)),
+                    ..Self::default()
                 }
             }
-            Err(_) => Self { devices: vec![] },
+            Err(_) => Self::default(),
         }
     }
 
@@ -198,179 +422,805 @@ impl UsbIpServer {
                     devs.push(d)
                 }
                 Self {
-                    devices: Self::with_devices(devs),
+                    devices: Mutex::new(Self::with_devices(devs)),
+                    ..Self::default()
+                }
+            }
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+/// A device imported by a connection, busy until dropped
+///
+/// Shared with the endpoint queues, so the device stays busy until their handlers return
+struct ImportedDevice {
+    server: Arc<UsbIpServer>,
+    device: Arc<UsbDevice>,
+    /// Notified when the device is removed from the server
+    removed: Arc<Notify>,
+}
+
+impl Drop for ImportedDevice {
+    fn drop(&mut self) {
+        info!("Release device {}", self.device.bus_id);
+        let mut imports = self.server.imports.lock().unwrap();
+        // the device may have been removed and imported again since
+        if imports
+            .get(&self.device.bus_id)
+            .is_some_and(|removed| Arc::ptr_eq(removed, &self.removed))
+        {
+            imports.remove(&self.device.bus_id);
+        }
+    }
+}
+
+/// A URB submitted but not completed yet
+struct PendingUrb {
+    header: UsbIpHeaderBasic,
+    /// Transfer buffer length
+    length: usize,
+    /// Cancelled by USBIP_CMD_UNLINK or a removed device, its endpoint queue removes it without replying
+    unlinked: bool,
+    /// Counts the URB on its device until it is removed
+    _usage: UrbUsageGuard,
+}
+
+/// URBs submitted but not completed yet, by seq_num
+type PendingUrbs = Arc<Mutex<HashMap<u32, PendingUrb>>>;
+
+/// Replies are sent to the writer half of a connection through this channel
+///
+/// An error ends the connection, like a failed write would
+type ReplySender = mpsc::UnboundedSender<Result<Reply>>;
+
+/// An encoded reply, written with one vectored write so the payload is not copied
+#[derive(Default)]
+struct Reply {
+    header: Vec<u8>,
+    payload: Vec<u8>,
+    trailer: Vec<u8>,
+}
+
+impl From<Vec<u8>> for Reply {
+    fn from(header: Vec<u8>) -> Self {
+        Self {
+            header,
+            ..Reply::default()
+        }
+    }
+}
+
+impl Reply {
+    /// USBIP_RET_SUBMIT with the IN data as payload
+    fn ret_submit(ret: RetSubmit, pool: &BufferPool) -> Self {
+        let mut header = pool.get();
+        ret.encode_header(&mut header);
+        let mut trailer = if ret.iso_packet_descriptor.is_empty() {
+            vec![]
+        } else {
+            pool.get()
+        };
+        ret.encode_iso_packets(&mut trailer);
+        Self {
+            header,
+            payload: ret.transfer_buffer,
+            trailer,
+        }
+    }
+
+    /// USBIP_RET_SUBMIT of a URB failed with `status` without handling it
+    fn ret_submit_error(header: UsbIpHeaderBasic, status: i32) -> Self {
+        let ret = RetSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_SUBMIT,
+                ..header
+            },
+            status,
+            ..RetSubmit::default()
+        };
+        let mut reply = Vec::with_capacity(RetSubmit::SIZE);
+        ret.encode_header(&mut reply);
+        reply.into()
+    }
+}
+
+fn send_reply(replies: &ReplySender, reply: impl Into<Reply>) -> Result<()> {
+    replies
+        .send(Ok(reply.into()))
+        .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))
+}
+
+/// Max number of replies written at once
+const MAX_REPLY_BATCH: usize = 64;
+
+/// Write `replies` with as few vectored writes as possible
+async fn write_replies<T: AsyncWriteExt + Unpin>(socket: &mut T, replies: &[Reply]) -> Result<()> {
+    let mut slices: Vec<IoSlice> = replies
+        .iter()
+        .flat_map(|reply| [&reply.header, &reply.payload, &reply.trailer])
+        .filter(|buf| !buf.is_empty())
+        .map(|buf| IoSlice::new(buf))
+        .collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let written = socket.write_vectored(slices).await?;
+        if written == 0 {
+            return Err(std::io::Error::from(ErrorKind::WriteZero));
+        }
+        IoSlice::advance_slices(&mut slices, written);
+    }
+    Ok(())
+}
+
+/// Spawn a task into `tasks` handling URBs to one endpoint of `device` in submission order
+///
+/// URBs to different endpoints are handled concurrently, so a pending interrupt IN poll does not stall bulk transfers.
+/// Control transfers to endpoint zero share one queue whatever their direction.
+/// Unlinking a URB whose handler is running does not interrupt it, the next URB waits for the handler to return
+/// and the URB counts toward the limits until then.
+fn spawn_endpoint_queue(
+    imported: Arc<ImportedDevice>,
+    pending: PendingUrbs,
+    replies: ReplySender,
+    metrics: Arc<Metrics>,
+    pool: Arc<BufferPool>,
+    tasks: &mut tokio::task::JoinSet<()>,
+) -> mpsc::UnboundedSender<CmdSubmit> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<CmdSubmit>();
+    tasks.spawn(async move {
+        let device = imported.device.clone();
+        while let Some(urb) = rx.recv().await {
+            let header = urb.header;
+            {
+                let mut pending = pending.lock().unwrap();
+                if pending.get(&header.seq_num).is_none_or(|urb| urb.unlinked) {
+                    trace!("Skip unlinked URB {}", header.seq_num);
+                    pending.remove(&header.seq_num);
+                    pool.put(urb.transfer_buffer);
+                    continue;
+                }
+            }
+            let started = std::time::Instant::now();
+            let out_length = urb.transfer_buffer.len() as u32;
+            let (setup, start_frame) = (urb.setup, urb.start_frame);
+            // handlers are blocking, e.g. libusb transfers
+            let handler_device = device.clone();
+            let task = tokio::task::spawn_blocking(move || {
+                let res = handle_submit(&handler_device, &urb);
+                (res, urb.transfer_buffer)
+            });
+            // a blocking handler cannot be interrupted, so an unlinked URB keeps its usage until it returns
+            let res = match task.await {
+                Ok((res, out_data)) => {
+                    pool.put(out_data);
+                    res
+                }
+                Err(err) => Err(std::io::Error::other(err).into()),
+            };
+            let mut ret = RetSubmit {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_RET_SUBMIT,
+                    ..header
+                },
+                setup,
+                ..RetSubmit::default()
+            };
+            match res {
+                Ok(Completion {
+                    data,
+                    iso_packets,
+                    status,
+                }) => {
+                    trace!("<-Resp {:02x?}", data);
+                    ret.status = status;
+                    ret.start_frame = start_frame;
+                    ret.number_of_packets = iso_packets.len() as u32;
+                    ret.error_count = iso_packets.iter().filter(|p| p.status != 0).count() as u32;
+                    if header.direction == USBIP_DIR_OUT {
+                        // OUT data is consumed, nothing is sent back
+                        ret.actual_length = if iso_packets.is_empty() {
+                            out_length
+                        } else {
+                            iso_packets.iter().map(|p| p.actual_length).sum()
+                        };
+                    } else {
+                        ret.actual_length = data.len() as u32;
+                        ret.transfer_buffer = data;
+                    }
+                    ret.iso_packet_descriptor = iso_packets;
+                }
+                Err(UrbError::Io(err)) => {
+                    replies.send(Err(err)).ok();
+                    break;
+                }
+                Err(err) => {
+                    debug!("<-Error {:?}", err);
+                    ret.status = -err.errno().unwrap();
                 }
             }
-            Err(_) => Self { devices: vec![] },
+            // keep the lock until the reply is queued, so that it precedes any USBIP_RET_UNLINK for it
+            let mut pending = pending.lock().unwrap();
+            if pending
+                .remove(&header.seq_num)
+                .is_none_or(|urb| urb.unlinked)
+            {
+                info!("URB {} unlinked", header.seq_num);
+                continue;
+            }
+            metrics.completed(&device.bus_id, header.endpoint_address(), &ret, started);
+            if let Some(capture) = &device.capture {
+                capture.complete(&device, &ret);
+            }
+            if send_reply(&replies, Reply::ret_submit(ret, &pool)).is_err() {
+                // connection closed
+                break;
+            }
+        }
+    });
+    tx
+}
+
+/// A URB handled by the device
+struct Completion {
+    /// IN data
+    data: Vec<u8>,
+    iso_packets: Vec<IsoPacketDescriptor>,
+    /// Zero or a negative errno, the URB completes with data anyway
+    status: i32,
+}
+
+/// Pass a URB to the handlers of `device`, returning IN data and iso packet results
+fn handle_submit(device: &UsbDevice, urb: &CmdSubmit) -> UrbResult<Completion> {
+    // checked on submission
+    let (usb_ep, intf) = device
+        .find_ep(urb.header.endpoint_address())
+        .ok_or(UrbError::Stall)?;
+    trace!("->Endpoint {:02x?}", usb_ep);
+    if urb.has_flags(USBIP_URB_DIR_IN) != (urb.header.direction == USBIP_DIR_IN) {
+        debug!(
+            "URB_DIR_IN does not match the direction of URB {}",
+            urb.header.seq_num
+        );
+    }
+    if usb_ep.attributes == EndpointAttributes::Isochronous as u8 {
+        trace!("->Iso packets {:?}", urb.iso_packet_descriptor);
+        let (data, iso_packets) = device.handle_iso_urb(
+            usb_ep,
+            intf,
+            &urb.iso_packet_descriptor,
+            &urb.transfer_buffer,
+        )?;
+        return Ok(Completion {
+            data,
+            iso_packets,
+            status: 0,
+        });
+    }
+
+    trace!("->Setup {:02x?}", urb.setup);
+    let mut data = device.handle_urb(
+        usb_ep,
+        intf,
+        urb.transfer_buffer_length,
+        urb.setup,
+        &urb.transfer_buffer,
+    )?;
+    let mut status = 0;
+    match usb_ep.direction() {
+        Direction::In => status = fit_in_data(urb, usb_ep, &mut data),
+        Direction::Out => {
+            let len = urb.transfer_buffer.len();
+            if urb.has_flags(USBIP_URB_ZERO_PACKET)
+                && !usb_ep.is_ep0()
+                && len > 0
+                && len.is_multiple_of(usb_ep.max_packet_size as usize)
+            {
+                // the transfer ends with a zero length packet instead of a short one
+                let intf = intf.ok_or(UrbError::Stall)?;
+                intf.handler
+                    .lock()
+                    .unwrap()
+                    .handle_zero_length_packet(intf, usb_ep)?;
+            }
         }
     }
+    Ok(Completion {
+        data,
+        iso_packets: vec![],
+        status,
+    })
+}
+
+/// Fit IN data of a non-isochronous URB into its transfer buffer, return the status of the URB
+///
+/// The host ends a control transfer after the requested length, while bulk and interrupt data beyond it is an overflow
+fn fit_in_data(urb: &CmdSubmit, ep: UsbEndpoint, data: &mut Vec<u8>) -> i32 {
+    let length = urb.transfer_buffer_length as usize;
+    if data.len() > length {
+        data.truncate(length);
+        if !ep.is_ep0() {
+            warn!(
+                "Handler of endpoint {:02x} returned more than {} bytes",
+                ep.address, length
+            );
+            return -EOVERFLOW;
+        }
+    } else if data.len() < length && urb.has_flags(USBIP_URB_SHORT_NOT_OK) {
+        return -EREMOTEIO;
+    }
+    0
 }
 
 async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-    mut socket: &mut T,
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    info: ConnectionInfo,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
+    let (replies, reply_rx) = mpsc::unbounded_channel();
+    let pool = Arc::new(BufferPool::default());
+    let mut queues = tokio::task::JoinSet::new();
+
+    // replies are written in completion order, the client matches them by seq_num
+    let res = tokio::try_join!(
+        read_commands(
+            &mut reader,
+            server,
+            info,
+            replies,
+            pool.clone(),
+            &mut queues
+        ),
+        write_reply_batches(&mut writer, reply_rx, &pool)
+    );
+    // the imported device is released once its endpoint queues are done
+    while queues.join_next().await.is_some() {}
+    res?;
+    Ok(())
+}
+
+/// Write replies from `reply_rx` until it is closed, returning their buffers to `pool`
+async fn write_reply_batches<T: AsyncWriteExt + Unpin>(
+    writer: &mut T,
+    mut reply_rx: mpsc::UnboundedReceiver<Result<Reply>>,
+    pool: &BufferPool,
+) -> Result<()> {
+    let mut batch = Vec::with_capacity(MAX_REPLY_BATCH);
+    while let Some(reply) = reply_rx.recv().await {
+        batch.push(reply?);
+        // write replies completed meanwhile together
+        while batch.len() < MAX_REPLY_BATCH {
+            match reply_rx.try_recv() {
+                Ok(reply) => batch.push(reply?),
+                Err(_) => break,
+            }
+        }
+        write_replies(writer, &batch).await?;
+        for reply in batch.drain(..) {
+            pool.put(reply.header);
+            pool.put(reply.payload);
+            pool.put(reply.trailer);
+        }
+    }
+    Ok(())
+}
+
+async fn read_commands<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
     server: Arc<UsbIpServer>,
+    info: ConnectionInfo,
+    replies: ReplySender,
+    pool: Arc<BufferPool>,
+    queues: &mut tokio::task::JoinSet<()>,
 ) -> Result<()> {
-    let mut current_import_device = None;
+    // released when the connection is closed and its endpoint queues are done
+    let mut current_import_device: Option<Arc<ImportedDevice>> = None;
+    let mut endpoint_queues: HashMap<u8, mpsc::UnboundedSender<CmdSubmit>> = HashMap::new();
+    let pending = PendingUrbs::default();
+    let mut shutdown = server.shutdown.subscribe();
+    let handshake_deadline = server
+        .handshake_timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
     loop {
-        let mut command = [0u8; 4];
-        if let Err(err) = socket.read_exact(&mut command).await {
-            if err.kind() == ErrorKind::UnexpectedEof {
+        let timeout = async {
+            match (
+                &current_import_device,
+                handshake_deadline,
+                server.idle_timeout,
+            ) {
+                (None, Some(deadline), _) => tokio::time::sleep_until(deadline).await,
+                (Some(_), _, Some(timeout)) => loop {
+                    // restarted by each command
+                    tokio::time::sleep(timeout).await;
+                    if pending.lock().unwrap().is_empty() {
+                        break;
+                    }
+                },
+                _ => std::future::pending().await,
+            }
+        };
+        let removed = async {
+            match &current_import_device {
+                Some(imported) => imported.removed.notified().await,
+                None => std::future::pending().await,
+            }
+        };
+        let res = tokio::select! {
+            res = UsbIpCommand::read_with_buffer(socket, server.limits.max_transfer_length, || pool.get()) => Some(res),
+            _ = shutdown.wait_for(|&shutdown| shutdown) => {
+                // queued URBs are completed before the connection is closed
+                info!("Server shutting down, closing the connection");
+                return Ok(());
+            }
+            _ = removed => None,
+            _ = timeout => {
+                info!("Connection timed out, closing the connection");
+                return Ok(());
+            }
+        };
+        let Some(res) = res else {
+            info!("Imported device removed, closing the connection");
+            fail_pending_urbs(&pending, &replies, ENODEV);
+            return Ok(());
+        };
+        let command = match res {
+            Ok(command) => command,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
                 info!("Remote closed the connection");
                 return Ok(());
-            } else {
-                return Err(err);
             }
-        }
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                // the length of an unknown command is unknown, so the stream cannot be resynchronized
+                warn!("Got unknown command, closing the connection: {}", err);
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
         match command {
-            [0x01, 0x11, 0x80, 0x05] => {
+            UsbIpCommand::OpReqDevlist(req) => {
                 trace!("Got OP_REQ_DEVLIST");
-                let _status = socket.read_u32().await?;
-
-                // OP_REP_DEVLIST
-                socket.write_u32(0x01110005).await?;
-                socket.write_u32(0).await?;
-                socket.write_u32(server.devices.len() as u32).await?;
-                for dev in &server.devices {
-                    dev.write_dev_with_interfaces(&mut socket).await?;
+                if req.version != USBIP_VERSION {
+                    warn!("Unsupported USB/IP version {:04x}", req.version);
+                    let rep = OpRepDevlist {
+                        status: ST_ERROR,
+                        ..OpRepDevlist::default()
+                    };
+                    let mut reply = vec![];
+                    rep.write(&mut reply).await?;
+                    send_reply(&replies, reply)?;
+                    return Ok(());
                 }
+                let rep = OpRepDevlist {
+                    devices: server
+                        .devices
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|dev| server.can_list(&info, dev))
+                        .filter(|dev| !(server.hide_busy_devices && server.is_busy(&dev.bus_id)))
+                        .map(|dev| (dev.device_info(), dev.interface_info()))
+                        .collect(),
+                    ..OpRepDevlist::default()
+                };
+                let mut reply = vec![];
+                rep.write(&mut reply).await?;
+                send_reply(&replies, reply)?;
                 trace!("Sent OP_REP_DEVLIST");
             }
-            [0x01, 0x11, 0x80, 0x03] => {
+            UsbIpCommand::OpReqImport(req) => {
                 trace!("Got OP_REQ_IMPORT");
-                let _status = socket.read_u32().await?;
-                let mut bus_id = [0u8; 32];
-                socket.read_exact(&mut bus_id).await?;
+                if req.version != USBIP_VERSION {
+                    warn!("Unsupported USB/IP version {:04x}", req.version);
+                    let rep = OpRepImport {
+                        status: ST_ERROR,
+                        ..OpRepImport::default()
+                    };
+                    let mut reply = vec![];
+                    rep.write(&mut reply).await?;
+                    send_reply(&replies, reply)?;
+                    return Ok(());
+                }
+                // release the previous import of this connection
                 current_import_device = None;
-                for device in &server.devices {
-                    let mut expected = device.bus_id.as_bytes().to_vec();
-                    expected.resize(32, 0);
-                    if expected == bus_id {
-                        current_import_device = Some(device);
-                        info!("Found device {:?}", device.path);
-                        break;
+                endpoint_queues.clear();
+                let rep = match server.try_import(&req.bus_id, &info) {
+                    Ok(imported) => {
+                        info!("Found device {:?}", imported.device.path);
+                        let rep = OpRepImport {
+                            device: Some(imported.device.device_info()),
+                            ..OpRepImport::default()
+                        };
+                        current_import_device = Some(Arc::new(imported));
+                        rep
                     }
-                }
-
-                // OP_REP_IMPORT
+                    Err(status) => {
+                        warn!("Cannot import device {}: status {}", req.bus_id, status);
+                        OpRepImport {
+                            status,
+                            ..OpRepImport::default()
+                        }
+                    }
+                };
+                let mut reply = vec![];
+                rep.write(&mut reply).await?;
+                send_reply(&replies, reply)?;
                 trace!("Sent OP_REP_IMPORT");
-                socket.write_u32(0x01110003).await?;
-                if let Some(dev) = current_import_device {
-                    socket.write_u32(0).await?;
-                    dev.write_dev(&mut socket).await?;
-                } else {
-                    socket.write_u32(1).await?;
-                }
             }
-            [0x00, 0x00, 0x00, 0x01] => {
+            UsbIpCommand::CmdSubmit(cmd) => {
                 trace!("Got USBIP_CMD_SUBMIT");
-                let seq_num = socket.read_u32().await?;
-                let dev_id = socket.read_u32().await?;
-                let direction = socket.read_u32().await?;
-                let ep = socket.read_u32().await?;
-                let _transfer_flags = socket.read_u32().await?;
-                let transfer_buffer_length = socket.read_u32().await?;
-                let _start_frame = socket.read_u32().await?;
-                let _number_of_packets = socket.read_u32().await?;
-                let _interval = socket.read_u32().await?;
-                let mut setup = [0u8; 8];
-                socket.read_exact(&mut setup).await?;
-                let device = current_import_device.unwrap();
-                let real_ep = if direction == 0 { ep } else { ep | 0x80 };
-                let (usb_ep, intf) = device.find_ep(real_ep as u8).unwrap();
-                trace!("->Endpoint {:02x?}", usb_ep);
-                trace!("->Setup {:02x?}", setup);
-                let resp = device
-                    .handle_urb(socket, usb_ep, intf, transfer_buffer_length, setup)
-                    .await?;
-                trace!("<-Resp {:02x?}", resp);
-
-                // USBIP_RET_SUBMIT
-                // command
-                socket.write_u32(0x3).await?;
-                socket.write_u32(seq_num).await?;
-                socket.write_u32(dev_id).await?;
-                socket.write_u32(direction).await?;
-                socket.write_u32(ep).await?;
-                // status
-                socket.write_u32(0).await?;
-                // actual length
-                socket.write_u32(resp.len() as u32).await?;
-                // start frame
-                socket.write_u32(0).await?;
-                // number of packets
-                socket.write_u32(0).await?;
-                // error count
-                socket.write_u32(0).await?;
-                // setup
-                socket.write_all(&setup).await?;
-                // data
-                socket.write_all(&resp).await?;
+                let Some(imported) = &current_import_device else {
+                    warn!("Got USBIP_CMD_SUBMIT before import, closing the connection");
+                    return Ok(());
+                };
+                let device = imported.device.clone();
+                let real_ep = cmd.header.endpoint_address();
+                let length = cmd.transfer_buffer_length as usize;
+                let usage = match device.find_ep(real_ep) {
+                    _ if cmd.header.dev_id != device.bus_num << 16 | device.dev_num => {
+                        warn!("URB to unknown device {:08x}", cmd.header.dev_id);
+                        Err(-ENODEV)
+                    }
+                    None => {
+                        warn!("URB to unknown endpoint {:02x}", real_ep);
+                        Err(-EPIPE)
+                    }
+                    Some(_) if cmd.transfer_buffer_length > server.limits.max_transfer_length => {
+                        warn!("URB of {} bytes exceeds the limit", length);
+                        Err(-ENOMEM)
+                    }
+                    Some((ep, _)) if !fits_endpoint(&cmd, &ep, device.speed) => {
+                        warn!(
+                            "URB of {} bytes does not fit endpoint {:02x}",
+                            length, real_ep
+                        );
+                        Err(-EMSGSIZE)
+                    }
+                    Some(_)
+                        if !server
+                            .limits
+                            .connection
+                            .allows(&connection_usage(&pending), length) =>
+                    {
+                        warn!("Too many outstanding URBs on the connection");
+                        Err(-ENOMEM)
+                    }
+                    Some(_) => UrbUsageGuard::new(&device, length).ok_or_else(|| {
+                        warn!("Too many outstanding URBs on device {}", device.bus_id);
+                        -ENOMEM
+                    }),
+                };
+                let usage = match usage {
+                    Ok(usage) => usage,
+                    Err(status) => {
+                        server.metrics.submitted(&device.bus_id, real_ep);
+                        server.metrics.rejected(&device.bus_id, real_ep, status);
+                        if let Some(capture) = &device.capture {
+                            capture.submit(&device, &cmd);
+                            capture.failed(&device, cmd.header, status);
+                        }
+                        send_reply(&replies, Reply::ret_submit_error(cmd.header, status))?;
+                        pool.put(cmd.transfer_buffer);
+                        continue;
+                    }
+                };
+
+                pending.lock().unwrap().insert(
+                    cmd.header.seq_num,
+                    PendingUrb {
+                        header: cmd.header,
+                        length,
+                        unlinked: false,
+                        _usage: usage,
+                    },
+                );
+                server.metrics.submitted(&device.bus_id, real_ep);
+                if let Some(capture) = &device.capture {
+                    capture.submit(&device, &cmd);
+                }
+                // control transfers share one queue, the direction is that of the data stage
+                let queue_ep = if cmd.header.ep == 0 { 0 } else { real_ep };
+                let queue = endpoint_queues.entry(queue_ep).or_insert_with(|| {
+                    spawn_endpoint_queue(
+                        imported.clone(),
+                        pending.clone(),
+                        replies.clone(),
+                        server.metrics.clone(),
+                        pool.clone(),
+                        queues,
+                    )
+                });
+                if queue.send(cmd).is_err() {
+                    // the queue stops when the connection is closed
+                    return Err(std::io::Error::from(ErrorKind::BrokenPipe));
+                }
             }
-            [0x00, 0x00, 0x00, 0x02] => {
+            UsbIpCommand::CmdUnlink(cmd) => {
                 trace!("Got USBIP_CMD_UNLINK");
-                let seq_num = socket.read_u32().await?;
-                let dev_id = socket.read_u32().await?;
-                let direction = socket.read_u32().await?;
-                let ep = socket.read_u32().await?;
-                let _seq_num_submit = socket.read_u32().await?;
-                // 24 bytes of struct padding
-                let mut padding = [0u8; 6 * 4];
-                socket.read_exact(&mut padding).await?;
-
-                // USBIP_RET_UNLINK
-                // command
-                socket.write_u32(0x4).await?;
-                socket.write_u32(seq_num).await?;
-                socket.write_u32(dev_id).await?;
-                socket.write_u32(direction).await?;
-                socket.write_u32(ep).await?;
-                // status
-                socket.write_u32(0).await?;
-                socket.write_all(&mut padding).await?;
+                // -ECONNRESET if cancelled, 0 if it has completed already
+                let header = pending
+                    .lock()
+                    .unwrap()
+                    .get_mut(&cmd.unlink_seq_num)
+                    .filter(|urb| !urb.unlinked)
+                    .map(|urb| {
+                        // still counted until its endpoint queue is done with it
+                        urb.unlinked = true;
+                        urb.header
+                    });
+                let status = if let Some(header) = header {
+                    info!("Unlink URB {}", cmd.unlink_seq_num);
+                    if let Some(imported) = &current_import_device {
+                        let device = &imported.device;
+                        server
+                            .metrics
+                            .unlinked(&device.bus_id, header.endpoint_address());
+                        if let Some(capture) = &device.capture {
+                            capture.failed(device, header, -ECONNRESET);
+                        }
+                    }
+                    -ECONNRESET
+                } else {
+                    trace!("URB {} to unlink not found", cmd.unlink_seq_num);
+                    0
+                };
+
+                let ret = RetUnlink {
+                    header: UsbIpHeaderBasic {
+                        command: USBIP_RET_UNLINK,
+                        ..cmd.header
+                    },
+                    status,
+                };
+                let mut reply = pool.get();
+                ret.write(&mut reply).await?;
+                send_reply(&replies, reply)?;
+            }
+            UsbIpCommand::OpReqUnknown(op) => {
+                warn!(
+                    "Got unknown op code {:04x}, closing the connection",
+                    op.code
+                );
+                let rep = OpCommon {
+                    version: USBIP_VERSION,
+                    code: op.code & !0x8000,
+                    status: ST_ERROR,
+                };
+                let mut reply = Vec::with_capacity(OpCommon::SIZE);
+                rep.write(&mut reply).await?;
+                send_reply(&replies, reply)?;
+                return Ok(());
             }
-            _ => warn!("Got unknown command {:?}", command),
         }
     }
 }
 
-/// Spawn a USB/IP server at `addr` using [TcpListener]
-pub async fn server(addr: SocketAddr, server: UsbIpServer) {
-    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+/// Outstanding URBs of a connection
+fn connection_usage(pending: &PendingUrbs) -> UrbUsage {
+    let pending = pending.lock().unwrap();
+    UrbUsage {
+        urbs: pending.len(),
+        bytes: pending.values().map(|urb| urb.length).sum(),
+    }
+}
 
-    let server = async move {
-        let usbip_server = Arc::new(server);
-        loop {
-            match listener.accept().await {
-                Ok((mut socket, _addr)) => {
-                    info!("Got connection from {:?}", socket.peer_addr());
-                    let new_server = usbip_server.clone();
-                    tokio::spawn(async move {
-                        let res = handler(&mut socket, new_server).await;
+/// Cancel all pending URBs, completing them with `-errno`
+fn fail_pending_urbs(pending: &PendingUrbs, replies: &ReplySender, errno: i32) {
+    // endpoint queues do not reply to unlinked URBs
+    let mut pending = pending.lock().unwrap();
+    for urb in pending.values_mut().filter(|urb| !urb.unlinked) {
+        urb.unlinked = true;
+        send_reply(replies, Reply::ret_submit_error(urb.header, -errno)).ok();
+    }
+}
+
+/// A running [UsbIpServer], see [UsbIpServer::serve]
+///
+/// Dropping the handle leaves the server running
+pub struct UsbIpServerHandle<A = SocketAddr> {
+    server: Arc<UsbIpServer>,
+    local_addr: A,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl<A: Clone + std::fmt::Debug> UsbIpServerHandle<A> {
+    /// Address the server listens on
+    pub fn local_addr(&self) -> A {
+        self.local_addr.clone()
+    }
+
+    /// The running server, e.g. to add and remove devices
+    pub fn server(&self) -> &Arc<UsbIpServer> {
+        &self.server
+    }
+
+    /// Stop accepting connections, complete in-flight URBs and close all connections
+    pub async fn shutdown(self) {
+        info!("Shutting down server on {:?}", self.local_addr);
+        self.server.shutdown.send_replace(true);
+        self.task.await.ok();
+    }
+}
+
+async fn accept_connections<L: UsbIpListener>(mut listener: L, server: Arc<UsbIpServer>) {
+    let mut shutdown = server.shutdown.subscribe();
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((socket, info)) => {
+                    info!("Got connection from {:?}", info);
+                    if server.max_connections.is_some_and(|max| connections.len() >= max) {
+                        warn!("Too many connections, closing the connection from {:?}", info);
+                        continue;
+                    }
+                    let new_server = server.clone();
+                    connections.spawn(async move {
+                        let res = serve_connection_with_info(socket, new_server, info).await;
                         info!("Handler ended with {:?}", res);
                     });
                 }
                 Err(err) => {
                     warn!("Got error {:?}", err);
                 }
-            }
+            },
+            // reap finished connections
+            Some(_) = connections.join_next() => {}
+            _ = shutdown.wait_for(|&shutdown| shutdown) => break,
         }
-    };
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    info!("Server stopped");
+}
 
-    server.await
+/// Handle a single connection to `server` until it is closed
+///
+/// Any stream works as transport, e.g. a [tokio::net::UnixStream] or one half of [tokio::io::duplex]
+pub async fn serve_connection<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: T,
+    server: Arc<UsbIpServer>,
+) -> Result<()> {
+    serve_connection_with_info(socket, server, ConnectionInfo::default()).await
+}
+
+/// Handle a single connection to `server` from the client described by `info`
+pub async fn serve_connection_with_info<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    mut socket: T,
+    server: Arc<UsbIpServer>,
+    info: ConnectionInfo,
+) -> Result<()> {
+    handler(&mut socket, server, info).await
+}
+
+/// Spawn a USB/IP server at `addr` using [TcpListener]
+///
+/// Runs forever and panics if `addr` cannot be bound, use [UsbIpServer::serve] to handle errors and stop the server
+pub async fn server(addr: SocketAddr, server: UsbIpServer) {
+    let _handle = server.serve(addr).await.expect("bind to addr");
+    std::future::pending().await
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::util::tests::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     #[tokio::test]
     async fn req_empty_devlist() {
-        let server = UsbIpServer { devices: vec![] };
+        let server = UsbIpServer::new_simulated(vec![]);
 
         // OP_REQ_DEVLIST
         let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
-        handler(&mut mock_socket, Arc::new(server)).await.ok();
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
         // OP_REP_DEVLIST
         assert_eq!(
             mock_socket.output,
@@ -380,95 +1230,1179 @@ mod test {
 
     #[tokio::test]
     async fn req_sample_devlist() {
-        let intf_handler = Arc::new(Mutex::new(
-            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
-        ));
-        let server = UsbIpServer {
-            devices: vec![UsbDevice::new(0).with_interface(
-                ClassCode::CDC as u8,
-                cdc::CDC_ACM_SUBCLASS,
-                0x00,
-                "Test CDC ACM",
-                cdc::UsbCdcAcmHandler::endpoints(),
-                intf_handler.clone(),
-            )],
-        };
+        let server = cdc_server();
 
         // OP_REQ_DEVLIST
         let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
-        handler(&mut mock_socket, Arc::new(server)).await.ok();
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
         // OP_REP_DEVLIST
         // header: 0xC
         // device: 0x138
         // interface: 4 * 0x1
-        assert_eq!(mock_socket.output.len(), 0xC + 0x138 + 4 * 0x1);
+        assert_eq!(mock_socket.output.len(), 0xC + 0x138 + 4);
     }
 
     #[tokio::test]
     async fn req_import() {
-        let intf_handler = Arc::new(Mutex::new(
-            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
-        ));
-        let server = UsbIpServer {
-            devices: vec![UsbDevice::new(0).with_interface(
-                ClassCode::CDC as u8,
-                cdc::CDC_ACM_SUBCLASS,
-                0x00,
-                "Test CDC ACM",
-                cdc::UsbCdcAcmHandler::endpoints(),
-                intf_handler.clone(),
-            )],
-        };
+        let server = cdc_server();
 
-        // OP_REQ_IMPORT
-        let mut req = vec![0x01, 0x11, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00];
-        let mut path = "0".as_bytes().to_vec();
-        path.resize(32, 0);
-        req.extend(path);
+        let req = import_request("0").await;
         let mut mock_socket = MockSocket::new(req);
-        handler(&mut mock_socket, Arc::new(server)).await.ok();
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
         // OP_REQ_IMPORT
         assert_eq!(mock_socket.output.len(), 0x140);
     }
 
+    #[tokio::test]
+    async fn req_import_busy_device() {
+        let server = Arc::new(cdc_server().hide_busy_devices(true));
+
+        let req = import_request("0").await;
+
+        // imported by another connection
+        let imported = server
+            .try_import("0", &ConnectionInfo::default())
+            .ok()
+            .unwrap();
+        assert!(server.is_busy("0"));
+
+        let mut mock_socket = MockSocket::new(req.clone());
+        handler(&mut mock_socket, server.clone(), ConnectionInfo::default())
+            .await
+            .ok();
+        // OP_REP_IMPORT with ST_DEV_BUSY
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02]
+        );
+
+        // OP_REQ_DEVLIST
+        let mut mock_socket = MockSocket::new(vec![0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00]);
+        handler(&mut mock_socket, server.clone(), ConnectionInfo::default())
+            .await
+            .ok();
+        // OP_REP_DEVLIST without the busy device
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        drop(imported);
+        assert!(!server.is_busy("0"));
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone(), ConnectionInfo::default())
+            .await
+            .ok();
+        // OP_REP_IMPORT
+        assert_eq!(mock_socket.output.len(), 0x140);
+        // released when the connection is closed
+        assert!(!server.is_busy("0"));
+    }
+
+    #[tokio::test]
+    async fn req_import_unknown_device() {
+        let server = UsbIpServer::new_simulated(vec![]);
+
+        let req = import_request("1-1").await;
+        let mut mock_socket = MockSocket::new(req);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
+        // OP_REP_IMPORT with ST_NODEV
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04]
+        );
+    }
+
+    #[tokio::test]
+    async fn req_unsupported_version() {
+        let server = UsbIpServer::new_simulated(vec![]);
+
+        // OP_REQ_DEVLIST of version 1.0.6, then another one that is not handled
+        let mut mock_socket = MockSocket::new(vec![
+            0x01, 0x06, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00, //
+            0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
+        // OP_REP_DEVLIST with ST_ERROR
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05]
+        );
+    }
+
+    #[tokio::test]
+    async fn req_unknown_op_code() {
+        let server = UsbIpServer::new_simulated(vec![]);
+
+        // unknown OP_REQ, then garbage that is not read
+        let mut mock_socket = MockSocket::new(vec![
+            0x01, 0x11, 0x80, 0x06, 0x00, 0x00, 0x00, 0x00, //
+            0xde, 0xad, 0xbe, 0xef,
+        ]);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
+        // OP_REP with ST_ERROR
+        assert_eq!(
+            mock_socket.output,
+            [0x01, 0x11, 0x00, 0x06, 0x00, 0x00, 0x00, 0x05]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_command_closes_connection() {
+        let server = UsbIpServer::new_simulated(vec![]);
+
+        // unknown USBIP_CMD_*, then OP_REQ_DEVLIST
+        let mut mock_socket = MockSocket::new(vec![
+            0x00, 0x00, 0x00, 0x07, //
+            0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
+        assert!(mock_socket.output.is_empty());
+    }
+
+    /// USBIP_CMD_SUBMIT of a control IN transfer of wLength bytes
+    fn control_in_urb(seq_num: u32, dev_id: u32, ep: u32, setup: [u8; 8]) -> UsbIpCommand {
+        UsbIpCommand::CmdSubmit(CmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT,
+                seq_num,
+                dev_id,
+                direction: USBIP_DIR_IN,
+                ep,
+            },
+            transfer_flags: USBIP_URB_DIR_IN,
+            transfer_buffer_length: SetupPacket::parse(&setup).length as u32,
+            setup,
+            ..CmdSubmit::default()
+        })
+    }
+
+    /// USBIP_CMD_SUBMIT of a control transfer with OUT direction in the header
+    fn control_out_urb(seq_num: u32, setup: [u8; 8], data: &[u8]) -> UsbIpCommand {
+        UsbIpCommand::CmdSubmit(CmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT,
+                seq_num,
+                ..UsbIpHeaderBasic::default()
+            },
+            transfer_buffer_length: data.len() as u32,
+            setup,
+            transfer_buffer: data.to_vec(),
+            ..CmdSubmit::default()
+        })
+    }
+
     #[tokio::test]
     async fn req_import_get_device_desc() {
+        let server = cdc_server();
+
+        let mut req = import_request("0").await;
+        // GetDescriptor to Device
+        control_in_urb(1, 0, 0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .ok();
+        // OP_REQ_IMPORT + USBIP_CMD_SUBMIT + Device Descriptor
+        assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
+    }
+
+    #[tokio::test]
+    async fn control_direction() {
+        let server = cdc_server();
+
+        let mut req = import_request("0").await;
+        // OUT data stage in an IN URB
+        control_in_urb(1, 0, 0, [0x21, 0x20, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        // SetConfiguration without data stage, in both directions
+        control_in_urb(2, 0, 0, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        control_out_urb(3, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], &[])
+            .write(&mut req)
+            .await
+            .unwrap();
+        // data without data stage
+        control_out_urb(4, [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], &[0x01])
+            .write(&mut req)
+            .await
+            .unwrap();
+        // GetDescriptor to Device
+        control_in_urb(5, 0, 0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        // replies of endpoint zero are in submission order
+        let output = &mock_socket.output[0x140..];
+        assert_eq!(output.len(), 5 * 0x30 + 0x12);
+        let statuses = parse_ret_submits(output, 5);
+        assert_eq!(statuses, [(1, -EPIPE), (2, 0), (3, 0), (4, -EPIPE), (5, 0)]);
+        // bLength, bDescriptorType
+        assert_eq!(output[5 * 0x30..5 * 0x30 + 2], [0x12, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn stall_unsupported_request() {
         let intf_handler = Arc::new(Mutex::new(
-            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            Box::new(hid::UsbHidKeyboardHandler::new_keyboard())
+                as Box<dyn UsbInterfaceHandler + Send>,
         ));
-        let server = UsbIpServer {
-            devices: vec![UsbDevice::new(0).with_interface(
-                ClassCode::CDC as u8,
-                cdc::CDC_ACM_SUBCLASS,
-                0x00,
-                "Test CDC ACM",
-                cdc::UsbCdcAcmHandler::endpoints(),
-                intf_handler.clone(),
-            )],
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Test HID",
+            vec![],
+            intf_handler,
+        )]);
+
+        let mut req = import_request("0").await;
+        // GetReport to Interface
+        control_in_urb(1, 0, 0, [0xA1, 0x01, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .unwrap();
+        // OP_REQ_IMPORT + USBIP_RET_SUBMIT without data
+        assert_eq!(mock_socket.output.len(), 0x140 + 0x30);
+        // -EPIPE
+        assert_eq!(mock_socket.output[0x154..0x158], (-32i32).to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn malformed_urbs() {
+        let server = Arc::new(cdc_server());
+
+        let mut req = import_request("0").await;
+        // GetDescriptor to Device of another device
+        control_in_urb(
+            1,
+            0x00010002,
+            0,
+            [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+        )
+        .write(&mut req)
+        .await
+        .unwrap();
+        // unknown endpoint
+        control_in_urb(2, 0, 5, [0; 8])
+            .write(&mut req)
+            .await
+            .unwrap();
+        // GetDescriptor to an unknown string
+        control_in_urb(3, 0, 0, [0x80, 0x06, 0x20, 0x03, 0x09, 0x04, 0xff, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        // class request to an unknown interface
+        control_in_urb(4, 0, 0, [0xa1, 0x01, 0x00, 0x00, 0x07, 0x00, 0x08, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        // vendor request to a device without device handler
+        control_in_urb(5, 0, 0, [0xc0, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server, ConnectionInfo::default())
+            .await
+            .unwrap();
+
+        // OP_REP_IMPORT + 5 * USBIP_RET_SUBMIT
+        let output = &mock_socket.output;
+        assert_eq!(output.len(), 0x140 + 5 * 0x30);
+        let statuses = parse_ret_submits(&output[0x140..], 5);
+        assert_eq!(
+            statuses,
+            vec![
+                (1, -ENODEV),
+                (2, -EPIPE),
+                (3, -EPIPE),
+                (4, -EPIPE),
+                (5, -EPIPE)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn submit_before_import_closes_connection() {
+        let server = Arc::new(cdc_server());
+
+        let mut req = vec![];
+        control_in_urb(1, 0, 0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server, ConnectionInfo::default())
+            .await
+            .unwrap();
+        assert!(mock_socket.output.is_empty());
+    }
+
+    /// Blocks in every transfer until released, with a flag set while it runs
+    struct BusyHandler {
+        running: Arc<AtomicBool>,
+        started: Arc<Notify>,
+        release: std::sync::mpsc::Receiver<()>,
+        reply: Vec<u8>,
+    }
+
+    impl UsbInterfaceHandler for BusyHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> UrbResult<Vec<u8>> {
+            self.running.store(true, Ordering::SeqCst);
+            self.started.notify_one();
+            self.release.recv().ok();
+            self.running.store(false, Ordering::SeqCst);
+            Ok(self.reply.clone())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// Observes and releases a [BusyHandler], dropping it releases all transfers
+    struct Busy {
+        running: Arc<AtomicBool>,
+        started: Arc<Notify>,
+        release: std::sync::mpsc::Sender<()>,
+    }
+
+    /// A [BusyHandler] replying `reply` to every transfer
+    fn busy_handler(reply: Vec<u8>) -> (Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>, Busy) {
+        let (release, release_rx) = std::sync::mpsc::channel();
+        let busy = Busy {
+            running: Arc::new(AtomicBool::new(false)),
+            started: Arc::new(Notify::new()),
+            release,
         };
+        let handler = Arc::new(Mutex::new(Box::new(BusyHandler {
+            running: busy.running.clone(),
+            started: busy.started.clone(),
+            release: release_rx,
+            reply,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        (handler, busy)
+    }
 
-        // OP_REQ_IMPORT
-        let mut req = vec![0x01, 0x11, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00];
-        let mut path = "0".as_bytes().to_vec();
-        path.resize(32, 0);
-        req.extend(path);
-        // USBIP_CMD_SUBMIT
-        req.extend(vec![
-            0x00, 0x00, 0x00, 0x01, // command
-            0x00, 0x00, 0x00, 0x01, // seq num
-            0x00, 0x00, 0x00, 0x00, // dev id
-            0x00, 0x00, 0x00, 0x01, // IN
-            0x00, 0x00, 0x00, 0x00, // ep 0
-            0x00, 0x00, 0x00, 0x00, // transfer flags
-            0x00, 0x00, 0x00, 0x00, // transfer buffer length
-            0x00, 0x00, 0x00, 0x00, // start frame
-            0x00, 0x00, 0x00, 0x00, // number of packets
-            0x00, 0x00, 0x00, 0x00, // interval
-            0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00, // GetDescriptor to Device
-        ]);
+    /// A device whose interrupt IN endpoint 0x81 replies one byte once released, like a device
+    /// with no pending report
+    fn slow_interrupt_server() -> (UsbIpServer, Busy) {
+        let (intf_handler, busy) = busy_handler(vec![0x01]);
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            "Test Slow Interrupt",
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 0x08,
+                interval: 10,
+            }],
+            intf_handler,
+        )]);
+        (server, busy)
+    }
+
+    /// USBIP_CMD_SUBMIT of an interrupt IN transfer of `length` bytes
+    fn interrupt_in_urb(seq_num: u32, ep: u8, length: u32) -> UsbIpCommand {
+        UsbIpCommand::CmdSubmit(CmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT,
+                seq_num,
+                dev_id: 0,
+                direction: USBIP_DIR_IN,
+                ep: (ep & 0x7F) as u32,
+            },
+            transfer_flags: USBIP_URB_DIR_IN,
+            transfer_buffer_length: length,
+            interval: 10,
+            ..CmdSubmit::default()
+        })
+    }
+
+    /// USBIP_CMD_UNLINK of URB `unlink_seq_num` to endpoint 0x81
+    fn unlink_urb(seq_num: u32, unlink_seq_num: u32) -> UsbIpCommand {
+        UsbIpCommand::CmdUnlink(CmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK,
+                seq_num,
+                dev_id: 0,
+                direction: USBIP_DIR_IN,
+                ep: 1,
+            },
+            unlink_seq_num,
+        })
+    }
+
+    #[tokio::test]
+    async fn pipelined_urbs_reply_out_of_order() {
+        let (server, busy) = slow_interrupt_server();
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(socket, Arc::new(server)));
+        let mut req = import_request("0").await;
+        interrupt_in_urb(1, 0x81, 8).write(&mut req).await.unwrap();
+        // GetDescriptor to Device
+        control_in_urb(2, 0, 0, [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00])
+            .write(&mut req)
+            .await
+            .unwrap();
+        client.write_all(&req).await.unwrap();
+        OpRepImport::read(&mut client).await.unwrap();
+
+        // the device descriptor does not wait for the interrupt transfer
+        let ret = RetSubmit::read(&mut client, Direction::In).await.unwrap();
+        assert_eq!(ret.header.seq_num, 2);
+        assert_eq!(ret.transfer_buffer.len(), 0x12);
+        busy.release.send(()).unwrap();
+        let ret = RetSubmit::read(&mut client, Direction::In).await.unwrap();
+        assert_eq!(ret.header.seq_num, 1);
+        assert_eq!(ret.transfer_buffer, [0x01]);
+    }
+
+    #[tokio::test]
+    async fn unlink_pending_urb() {
+        let (server, busy) = slow_interrupt_server();
+        let (mut client, socket) = tokio::io::duplex(4096);
+        let connection = tokio::spawn(serve_connection(socket, Arc::new(server)));
+        let mut req = import_request("0").await;
+        interrupt_in_urb(1, 0x81, 8).write(&mut req).await.unwrap();
+        client.write_all(&req).await.unwrap();
+        OpRepImport::read(&mut client).await.unwrap();
+
+        busy.started.notified().await;
+        unlink_urb(2, 1).write(&mut client).await.unwrap();
+        let unlink = RetUnlink::read(&mut client).await.unwrap();
+        assert_eq!(unlink.header.seq_num, 2);
+        assert_eq!(unlink.status, -ECONNRESET);
+
+        // no USBIP_RET_SUBMIT once the handler returns
+        busy.release.send(()).unwrap();
+        client.shutdown().await.unwrap();
+        connection.await.unwrap().unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unlink_completed_urb() {
+        let mut req = import_request("0").await;
+        unlink_urb(2, 1).write(&mut req).await.unwrap();
         let mut mock_socket = MockSocket::new(req);
-        handler(&mut mock_socket, Arc::new(server)).await.ok();
-        // OP_REQ_IMPORT + USBIP_CMD_SUBMIT + Device Descriptor
-        assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
+        handler(
+            &mut mock_socket,
+            Arc::new(slow_interrupt_server().0),
+            ConnectionInfo::default(),
+        )
+        .await
+        .unwrap();
+        // OP_REQ_IMPORT + USBIP_RET_UNLINK
+        assert_eq!(mock_socket.output.len(), 0x140 + 0x30);
+        assert_eq!(mock_socket.output[0x154..0x158], [0x00, 0x00, 0x00, 0x00]);
+    }
+
+    /// A device with a [BusyHandler] on interface 0
+    fn busy_server() -> (UsbIpServer, Busy) {
+        let (intf_handler, busy) = busy_handler(vec![]);
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test Busy",
+            vec![],
+            intf_handler,
+        )]);
+        (server, busy)
+    }
+
+    // class request to interface 0
+    const CLASS_REQUEST: SetupPacket = SetupPacket {
+        request_type: 0x21,
+        request: 0x01,
+        value: 0,
+        index: 0,
+        length: 0,
+    };
+
+    #[tokio::test]
+    async fn unlink_waits_for_running_handler() {
+        let (server, busy) = busy_server();
+        let (client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(socket, Arc::new(server)));
+        let device = UsbIpClient::new(client).import("0").await.unwrap();
+
+        // unlinked once the handler runs
+        tokio::select! {
+            _ = device.control_out(CLASS_REQUEST, &[]) => panic!("handler returned"),
+            _ = busy.started.notified() => {}
+        }
+        assert!(busy.running.load(Ordering::SeqCst));
+
+        // the next control transfer waits for the handler to return
+        let get_descriptor = SetupPacket {
+            request_type: 0x80,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Device as u16) << 8,
+            index: 0,
+            length: 0x12,
+        };
+        let next = device.control_in(get_descriptor);
+        tokio::pin!(next);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut next)
+            .await
+            .is_err());
+        busy.release.send(()).unwrap();
+        assert_eq!(next.await.unwrap().len(), 0x12);
+        assert!(!busy.running.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn unlinked_urb_counts_until_handler_returns() {
+        let (server, busy) = busy_server();
+        let server = server.with_connection_limits(UrbLimits {
+            max_urbs: 1,
+            ..UrbLimits::UNLIMITED
+        });
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(socket, Arc::new(server)));
+        client.write_all(&import_request("0").await).await.unwrap();
+        OpRepImport::read(&mut client).await.unwrap();
+
+        control_out_urb(1, CLASS_REQUEST.to_bytes(), &[])
+            .write(&mut client)
+            .await
+            .unwrap();
+        busy.started.notified().await;
+        let mut req = vec![];
+        unlink_urb(2, 1).write(&mut req).await.unwrap();
+        control_out_urb(3, CLASS_REQUEST.to_bytes(), &[])
+            .write(&mut req)
+            .await
+            .unwrap();
+        client.write_all(&req).await.unwrap();
+
+        let unlink = RetUnlink::read(&mut client).await.unwrap();
+        assert_eq!(unlink.status, -ECONNRESET);
+        // the unlinked URB is still handled, so the next one is beyond the limit
+        let ret = RetSubmit::read(&mut client, Direction::Out).await.unwrap();
+        assert_eq!((ret.header.seq_num, ret.status), (3, -ENOMEM));
+        busy.release.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn urb_limits() {
+        let mut req = import_request("0").await;
+        // vendor request with discarded OUT data
+        control_out_urb(
+            1,
+            [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00],
+            &[0xaa; 0x20],
+        )
+        .write(&mut req)
+        .await
+        .unwrap();
+        for seq_num in [2, 3] {
+            interrupt_in_urb(seq_num, 0x81, 8)
+                .write(&mut req)
+                .await
+                .unwrap();
+        }
+        let (server, busy) = slow_interrupt_server();
+        let server = server
+            .with_max_transfer_length(0x10)
+            .with_connection_limits(UrbLimits {
+                max_urbs: 1,
+                ..UrbLimits::UNLIMITED
+            });
+        let (mut client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(socket, Arc::new(server)));
+        client.write_all(&req).await.unwrap();
+        OpRepImport::read(&mut client).await.unwrap();
+
+        // too long, then beyond the connection limit while the first interrupt transfer is pending
+        let mut rejected = [0u8; 2 * 0x30];
+        client.read_exact(&mut rejected).await.unwrap();
+        assert_eq!(
+            parse_ret_submits(&rejected, 2),
+            [(1, -ENOMEM), (3, -ENOMEM)]
+        );
+        busy.release.send(()).unwrap();
+        let ret = RetSubmit::read(&mut client, Direction::In).await.unwrap();
+        assert_eq!((ret.header.seq_num, ret.status), (2, 0));
+    }
+
+    /// Returns 8 bytes on bulk IN whatever the requested length, counts bulk OUT transfers and
+    /// zero length packets
+    struct FixedBulkHandler {
+        out_transfers: usize,
+        zero_length_packets: usize,
+    }
+
+    impl UsbInterfaceHandler for FixedBulkHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> UrbResult<Vec<u8>> {
+            match ep.direction() {
+                Direction::In => Ok(vec![0x55; 8]),
+                Direction::Out => {
+                    self.out_transfers += 1;
+                    Ok(vec![])
+                }
+            }
+        }
+
+        fn handle_zero_length_packet(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+        ) -> UrbResult<()> {
+            self.zero_length_packets += 1;
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// USBIP_CMD_SUBMIT of a bulk transfer, with `length` bytes of OUT data
+    fn bulk_urb(seq_num: u32, ep: u32, flags: u32, length: u32) -> UsbIpCommand {
+        UsbIpCommand::CmdSubmit(CmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT,
+                seq_num,
+                dev_id: 0,
+                direction: ep >> 7,
+                ep: ep & 0x7F,
+            },
+            transfer_flags: flags,
+            transfer_buffer_length: length,
+            transfer_buffer: if ep & 0x80 == 0 {
+                vec![0xaa; length as usize]
+            } else {
+                vec![]
+            },
+            ..CmdSubmit::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn transfer_length_and_flags() {
+        let intf_handler = Arc::new(Mutex::new(Box::new(FixedBulkHandler {
+            out_transfers: 0,
+            zero_length_packets: 0,
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0x00,
+            0x00,
+            "Test Fixed Bulk",
+            vec![
+                UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                },
+                UsbEndpoint {
+                    address: 0x02,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                },
+            ],
+            intf_handler.clone(),
+        )]);
+
+        let mut req = import_request("0").await;
+        // too short for the data
+        bulk_urb(1, 0x81, USBIP_URB_DIR_IN, 4)
+            .write(&mut req)
+            .await
+            .unwrap();
+        // short transfer
+        bulk_urb(2, 0x81, USBIP_URB_DIR_IN | USBIP_URB_SHORT_NOT_OK, 16)
+            .write(&mut req)
+            .await
+            .unwrap();
+        bulk_urb(3, 0x81, USBIP_URB_DIR_IN, 16)
+            .write(&mut req)
+            .await
+            .unwrap();
+        // full packets ended by a zero length packet
+        bulk_urb(4, 0x02, USBIP_URB_ZERO_PACKET, 512)
+            .write(&mut req)
+            .await
+            .unwrap();
+        bulk_urb(5, 0x02, USBIP_URB_ZERO_PACKET, 500)
+            .write(&mut req)
+            .await
+            .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut output = &mock_socket.output[0x140..];
+        let mut replies = HashMap::new();
+        while !output.is_empty() {
+            let seq_num = u32::from_be_bytes(output[4..8].try_into().unwrap());
+            let status = i32::from_be_bytes(output[20..24].try_into().unwrap());
+            let actual_length = u32::from_be_bytes(output[24..28].try_into().unwrap());
+            let data_length = if seq_num <= 3 { actual_length } else { 0 };
+            replies.insert(seq_num, (status, actual_length));
+            output = &output[0x30 + data_length as usize..];
+        }
+        assert_eq!(replies[&1], (-EOVERFLOW, 4));
+        assert_eq!(replies[&2], (-EREMOTEIO, 8));
+        assert_eq!(replies[&3], (0, 8));
+        assert_eq!(replies[&4], (0, 512));
+        assert_eq!(replies[&5], (0, 500));
+
+        let mut handler = intf_handler.lock().unwrap();
+        let handler = handler.as_any().downcast_mut::<FixedBulkHandler>().unwrap();
+        assert_eq!(handler.out_transfers, 2);
+        assert_eq!(handler.zero_length_packets, 1);
+    }
+
+    /// Returns three bytes for each isochronous IN packet
+    struct IsoHandler;
+
+    impl UsbInterfaceHandler for IsoHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> UrbResult<Vec<u8>> {
+            Err(UrbError::Stall)
+        }
+
+        fn handle_iso_packet(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _length: u32,
+            _req: &[u8],
+        ) -> UrbResult<Vec<u8>> {
+            Ok(vec![0xAA, 0xBB, 0xCC])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn iso_in_urb() {
+        let intf_handler = Arc::new(Mutex::new(
+            Box::new(IsoHandler) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            ClassCode::Audio as u8,
+            0x02,
+            0x00,
+            "Test Iso",
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Isochronous as u8,
+                max_packet_size: 0x08,
+                interval: 1,
+            }],
+            intf_handler,
+        )]);
+
+        let mut req = import_request("0").await;
+        // two packets of 8 and 2 bytes
+        UsbIpCommand::CmdSubmit(CmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT,
+                seq_num: 1,
+                dev_id: 0,
+                direction: USBIP_DIR_IN,
+                ep: 1,
+            },
+            transfer_flags: USBIP_URB_ISO_ASAP | USBIP_URB_DIR_IN,
+            transfer_buffer_length: 0x0A,
+            start_frame: 0x10,
+            number_of_packets: 2,
+            interval: 1,
+            iso_packet_descriptor: vec![
+                IsoPacketDescriptor {
+                    offset: 0,
+                    length: 8,
+                    ..IsoPacketDescriptor::default()
+                },
+                IsoPacketDescriptor {
+                    offset: 8,
+                    length: 2,
+                    ..IsoPacketDescriptor::default()
+                },
+            ],
+            ..CmdSubmit::default()
+        })
+        .write(&mut req)
+        .await
+        .unwrap();
+        let mut mock_socket = MockSocket::new(req);
+        handler(
+            &mut mock_socket,
+            Arc::new(server),
+            ConnectionInfo::default(),
+        )
+        .await
+        .unwrap();
+        let ret = &mock_socket.output[0x140..];
+        // USBIP_RET_SUBMIT + packed data + iso packet descriptors
+        assert_eq!(ret.len(), 0x30 + 5 + 2 * 0x10);
+        // actual length, start frame, number of packets, error count
+        assert_eq!(
+            ret[0x18..0x28],
+            [
+                0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+                0x00, 0x01
+            ]
+        );
+        assert_eq!(ret[0x30..0x35], [0xAA, 0xBB, 0xCC, 0xAA, 0xBB]);
+        assert_eq!(
+            ret[0x35..0x45],
+            [
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+                0x00, 0x00
+            ]
+        );
+        // second packet overflows
+        assert_eq!(ret[0x51..0x55], (-75i32).to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn reuse_urb_buffers() {
+        let pool = BufferPool::default();
+        let mut pooled = Vec::with_capacity(0x100);
+        let ptr = pooled.as_ptr();
+        pooled.push(0);
+        pool.put(pooled);
+
+        // IN URBs leave the pooled buffer to OUT data
+        let mut req = vec![];
+        bulk_urb(1, 0x81, 0, 0x10).write(&mut req).await.unwrap();
+        bulk_urb(2, 0x02, 0, 0x10).write(&mut req).await.unwrap();
+        let mut reader = &req[..];
+        for seq_num in [1, 2] {
+            let cmd = UsbIpCommand::read_with_buffer(&mut reader, u32::MAX, || pool.get())
+                .await
+                .unwrap();
+            let UsbIpCommand::CmdSubmit(cmd) = cmd else {
+                panic!("expected USBIP_CMD_SUBMIT");
+            };
+            assert_eq!(cmd.header.seq_num, seq_num);
+            if seq_num == 2 {
+                assert_eq!(cmd.transfer_buffer.as_ptr(), ptr);
+            }
+        }
+
+        // header, IN data and iso packets of a reply are returned once written
+        let ret = RetSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_SUBMIT,
+                seq_num: 1,
+                dev_id: 0,
+                direction: USBIP_DIR_IN,
+                ep: 1,
+            },
+            actual_length: 4,
+            number_of_packets: 1,
+            transfer_buffer: vec![0xaa; 4],
+            iso_packet_descriptor: vec![IsoPacketDescriptor::default()],
+            ..RetSubmit::default()
+        };
+        let reply = Reply::ret_submit(ret, &pool);
+        let mut written = [&reply.header, &reply.payload, &reply.trailer].map(|buf| buf.as_ptr());
+        let (replies, reply_rx) = mpsc::unbounded_channel();
+        send_reply(&replies, reply).unwrap();
+        drop(replies);
+        let mut output = vec![];
+        write_reply_batches(&mut output, reply_rx, &pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            output.len(),
+            RetSubmit::SIZE + 4 + IsoPacketDescriptor::SIZE
+        );
+        let reused = [(); 3].map(|_| pool.get());
+        let mut reused = reused.each_ref().map(|buf| buf.as_ptr());
+        written.sort();
+        reused.sort();
+        assert_eq!(written, reused);
+    }
+
+    #[tokio::test]
+    async fn serve_bind_error() {
+        let handle = UsbIpServer::new_simulated(vec![])
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        // address in use
+        let res = UsbIpServer::new_simulated(vec![])
+            .serve(handle.local_addr())
+            .await;
+        assert!(res.is_err());
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_completes_pending_urbs() {
+        let (server, busy) = slow_interrupt_server();
+        let handle = server.serve("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = handle.local_addr();
+        let device = UsbIpClient::connect(addr)
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+
+        let urb = tokio::spawn(async move { device.interrupt_in(0x81, 8).await });
+        busy.started.notified().await;
+        // released once the shutdown started
+        tokio::join!(handle.shutdown(), async { busy.release.send(()).unwrap() });
+
+        assert_eq!(urb.await.unwrap().unwrap(), [0x01]);
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn max_connections() {
+        let handle = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .with_max_connections(1)
+            .with_tcp_keepalive(Duration::from_secs(60))
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut first = UsbIpClient::connect(handle.local_addr()).await.unwrap();
+        assert_eq!(first.list_devices().await.unwrap().len(), 1);
+        let mut second = UsbIpClient::connect(handle.local_addr()).await.unwrap();
+        assert!(second.list_devices().await.is_err());
+
+        drop(first);
+        // closed until the server notices
+        loop {
+            let mut third = UsbIpClient::connect(handle.local_addr()).await.unwrap();
+            if let Ok(devices) = third.list_devices().await {
+                assert_eq!(devices.len(), 1);
+                break;
+            }
+        }
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let server = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+                .with_handshake_timeout(Duration::from_millis(50)),
+        );
+        let (mut client, socket) = tokio::io::duplex(4096);
+        let connection = tokio::spawn(serve_connection(socket, server));
+
+        // closed without a reply
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        connection.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_releases_device() {
+        let (server, busy) = slow_interrupt_server();
+        let server = Arc::new(
+            server
+                .with_handshake_timeout(Duration::from_millis(100))
+                .with_idle_timeout(Duration::from_millis(100)),
+        );
+        let (client, socket) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(socket, server.clone()));
+        let device = UsbIpClient::new(client).import("0").await.unwrap();
+        assert!(server.is_busy("0"));
+
+        // a pending URB keeps the connection open
+        let urb = device.interrupt_in(0x81, 8);
+        tokio::pin!(urb);
+        tokio::select! {
+            _ = &mut urb => panic!("handler returned"),
+            _ = busy.started.notified() => {}
+        }
+        // the running handler keeps the clock from advancing by itself
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert!(server.is_busy("0"));
+        busy.release.send(()).unwrap();
+        assert_eq!(urb.await.unwrap(), [0x01]);
+        assert!(server.is_busy("0"));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!server.is_busy("0"));
+        assert!(device.interrupt_in(0x81, 8).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_connection_releases_device_after_handlers() {
+        let (server, busy) = slow_interrupt_server();
+        let server = Arc::new(server);
+        let (mut client, socket) = tokio::io::duplex(4096);
+        let mut connection = tokio::spawn(serve_connection(socket, server.clone()));
+
+        let mut req = import_request("0").await;
+        interrupt_in_urb(1, 0x81, 8).write(&mut req).await.unwrap();
+        client.write_all(&req).await.unwrap();
+        OpRepImport::read(&mut client).await.unwrap();
+
+        // close the connection while the handler runs
+        busy.started.notified().await;
+        drop(client);
+        // the connection waits for the handler
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut connection)
+                .await
+                .is_err()
+        );
+        assert!(server.is_busy("0"));
+
+        busy.release.send(()).unwrap();
+        connection.await.unwrap().ok();
+        assert!(!server.is_busy("0"));
+    }
+
+    #[tokio::test]
+    async fn remove_imported_device() {
+        let (server, busy) = slow_interrupt_server();
+        let handle = server.serve("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = handle.local_addr();
+        let device = UsbIpClient::connect(addr)
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+
+        let urb = tokio::spawn(async move { device.interrupt_in(0x81, 8).await });
+        busy.started.notified().await;
+        let removed = handle.server().remove_device("0").unwrap();
+        assert!(matches!(urb.await.unwrap(), Err(UrbError::NoDevice)));
+        busy.release.send(()).unwrap();
+
+        let mut client = UsbIpClient::connect(addr).await.unwrap();
+        assert!(client.list_devices().await.unwrap().is_empty());
+
+        handle.server().add_device(removed);
+        let mut client = UsbIpClient::connect(addr).await.unwrap();
+        assert_eq!(client.list_devices().await.unwrap().len(), 1);
+        // released by the closed connection
+        assert!(UsbIpClient::connect(addr)
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .is_ok());
+        handle.shutdown().await;
     }
 }
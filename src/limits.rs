@@ -0,0 +1,196 @@
+//! Caps on resources claimed by clients
+use super::*;
+
+/// Default max transfer buffer length of a URB, as the usbfs memory limit of Linux
+pub const DEFAULT_MAX_TRANSFER_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// Caps on URBs submitted and not completed yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UrbLimits {
+    /// Max number of outstanding URBs
+    pub max_urbs: usize,
+    /// Max total transfer buffer length of outstanding URBs
+    pub max_bytes: usize,
+}
+
+impl UrbLimits {
+    /// No caps
+    pub const UNLIMITED: UrbLimits = UrbLimits {
+        max_urbs: usize::MAX,
+        max_bytes: usize::MAX,
+    };
+
+    pub(crate) fn allows(&self, usage: &UrbUsage, length: usize) -> bool {
+        usage.urbs < self.max_urbs && usage.bytes.saturating_add(length) <= self.max_bytes
+    }
+}
+
+impl Default for UrbLimits {
+    /// 1024 URBs and 64 MiB
+    fn default() -> Self {
+        Self {
+            max_urbs: 1024,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Limits of a server, see [UsbIpServer::with_max_transfer_length] and [UsbIpServer::with_connection_limits]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ServerLimits {
+    pub(crate) max_transfer_length: u32,
+    pub(crate) connection: UrbLimits,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_transfer_length: DEFAULT_MAX_TRANSFER_LENGTH,
+            connection: UrbLimits::default(),
+        }
+    }
+}
+
+/// Outstanding URBs of a device or connection
+#[derive(Debug, Default)]
+pub(crate) struct UrbUsage {
+    pub(crate) urbs: usize,
+    pub(crate) bytes: usize,
+}
+
+/// Counts a URB as outstanding on a device until dropped
+pub(crate) struct UrbUsageGuard {
+    usage: Arc<Mutex<UrbUsage>>,
+    length: usize,
+}
+
+impl UrbUsageGuard {
+    /// Count a URB of `length` bytes on `device`, unless it exceeds the limits of the device
+    pub(crate) fn new(device: &UsbDevice, length: usize) -> Option<Self> {
+        let mut usage = device.usage.lock().unwrap();
+        if !device
+            .limits
+            .is_none_or(|limits| limits.allows(&usage, length))
+        {
+            return None;
+        }
+        usage.urbs += 1;
+        usage.bytes += length;
+        Some(Self {
+            usage: device.usage.clone(),
+            length,
+        })
+    }
+}
+
+impl Drop for UrbUsageGuard {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.urbs -= 1;
+        usage.bytes -= self.length;
+    }
+}
+
+/// Max data of one (micro)frame of an isochronous endpoint
+fn max_iso_payload(ep: &UsbEndpoint, speed: u32) -> u32 {
+    let size = ep.max_packet_size as u32 & 0x7ff;
+    let transactions = 1 + ((ep.max_packet_size as u32 >> 11) & 0x3);
+    match FromPrimitive::from_u32(speed) {
+        Some(UsbSpeed::Low) | Some(UsbSpeed::Full) => size.min(1023),
+        Some(UsbSpeed::High) => size.min(1024) * transactions,
+        // bursts are described in the SuperSpeed endpoint companion, which is not modeled
+        _ => 1024 * 16 * 3,
+    }
+}
+
+/// Whether the lengths of `urb` fit the type of `ep` on a device of `speed`
+pub(crate) fn fits_endpoint(urb: &CmdSubmit, ep: &UsbEndpoint, speed: u32) -> bool {
+    match FromPrimitive::from_u8(ep.attributes & 0x3) {
+        // wLength is 16 bits
+        Some(EndpointAttributes::Control) => urb.transfer_buffer_length <= u16::MAX as u32,
+        Some(EndpointAttributes::Isochronous) => {
+            let payload = max_iso_payload(ep, speed);
+            urb.iso_packet_descriptor
+                .iter()
+                .all(|packet| packet.length <= payload)
+                && urb.transfer_buffer_length as u64
+                    <= urb.iso_packet_descriptor.len() as u64 * payload as u64
+        }
+        _ => true,
+    }
+}
+
+impl UsbIpServer {
+    /// Reject URBs with a transfer buffer longer than `length`
+    ///
+    /// Their OUT data is discarded without buffering. Default to [DEFAULT_MAX_TRANSFER_LENGTH]
+    pub fn with_max_transfer_length(mut self, length: u32) -> Self {
+        self.limits.max_transfer_length = length;
+        self
+    }
+
+    /// Reject URBs beyond `limits` on each connection, default to [UrbLimits::default]
+    pub fn with_connection_limits(mut self, limits: UrbLimits) -> Self {
+        self.limits.connection = limits;
+        self
+    }
+}
+
+impl UsbDevice {
+    /// Reject URBs beyond `limits` on this device across connections, unlimited by default
+    pub fn with_limits(mut self, limits: UrbLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_limits() {
+        let device = UsbDevice::new(0).with_limits(UrbLimits {
+            max_urbs: 1,
+            max_bytes: 16,
+        });
+        let first = UrbUsageGuard::new(&device, 8).unwrap();
+        assert!(UrbUsageGuard::new(&device, 8).is_none());
+        drop(first);
+        assert!(UrbUsageGuard::new(&device, 17).is_none());
+        assert!(UrbUsageGuard::new(&device, 16).is_some());
+    }
+
+    #[test]
+    fn endpoint_limits() {
+        let control = UsbDevice::new(0).ep0_in;
+        let urb = CmdSubmit {
+            transfer_buffer_length: 0x10000,
+            ..CmdSubmit::default()
+        };
+        assert!(!fits_endpoint(&urb, &control, UsbSpeed::High as u32));
+
+        let iso = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Isochronous as u8,
+            // 2 transactions of 512 bytes per microframe
+            max_packet_size: 0x0800 | 512,
+            interval: 1,
+        };
+        let packet = IsoPacketDescriptor {
+            offset: 0,
+            length: 1024,
+            actual_length: 0,
+            status: 0,
+        };
+        let urb = CmdSubmit {
+            transfer_buffer_length: 2048,
+            number_of_packets: 2,
+            iso_packet_descriptor: vec![packet, packet],
+            ..CmdSubmit::default()
+        };
+        assert!(fits_endpoint(&urb, &iso, UsbSpeed::High as u32));
+        // one transaction of at most 1023 bytes per frame
+        assert!(!fits_endpoint(&urb, &iso, UsbSpeed::Full as u32));
+    }
+}
@@ -0,0 +1,132 @@
+//! Sources of connections to serve
+use super::*;
+use std::fmt::Debug;
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A client connection, available to device selection
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Address of the client, `None` if not on IP
+    pub peer_addr: Option<SocketAddr>,
+    /// Verified certificate of the client in DER, if it authenticated with TLS
+    pub client_certificate: Option<Vec<u8>>,
+}
+
+/// A listener accepting connections to a [UsbIpServer], see [UsbIpServer::serve_listener]
+///
+/// Implemented for [TcpListener] and, on Unix, [tokio::net::UnixListener]
+pub trait UsbIpListener: Send + 'static {
+    /// A connection to a client
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// Address of the listener
+    type Addr: Clone + Debug + Send + Sync + 'static;
+
+    /// Wait for the next connection
+    fn accept(&mut self) -> impl Future<Output = Result<(Self::Stream, ConnectionInfo)>> + Send;
+
+    /// Address the listener is bound to
+    fn local_addr(&self) -> Result<Self::Addr>;
+}
+
+impl UsbIpListener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> Result<(Self::Stream, ConnectionInfo)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        // replies are batched before writing, so Nagle's algorithm only adds latency
+        stream.set_nodelay(true)?;
+        Ok((
+            stream,
+            ConnectionInfo {
+                peer_addr: Some(addr),
+                ..ConnectionInfo::default()
+            },
+        ))
+    }
+
+    fn local_addr(&self) -> Result<Self::Addr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+/// A [TcpListener] enabling TCP keepalive on accepted connections
+///
+/// Dead clients are detected even when no URB is pending
+pub struct TcpKeepaliveListener {
+    listener: TcpListener,
+    keepalive: socket2::TcpKeepalive,
+}
+
+impl TcpKeepaliveListener {
+    /// Probe connections after `time` of inactivity
+    pub fn new(listener: TcpListener, time: Duration) -> Self {
+        Self {
+            listener,
+            keepalive: socket2::TcpKeepalive::new().with_time(time),
+        }
+    }
+}
+
+impl UsbIpListener for TcpKeepaliveListener {
+    type Stream = tokio::net::TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> Result<(Self::Stream, ConnectionInfo)> {
+        let (stream, info) = UsbIpListener::accept(&mut self.listener).await?;
+        socket2::SockRef::from(&stream).set_tcp_keepalive(&self.keepalive)?;
+        Ok((stream, info))
+    }
+
+    fn local_addr(&self) -> Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl UsbIpListener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    async fn accept(&mut self) -> Result<(Self::Stream, ConnectionInfo)> {
+        let (stream, _addr) = tokio::net::UnixListener::accept(self).await?;
+        Ok((stream, ConnectionInfo::default()))
+    }
+
+    fn local_addr(&self) -> Result<Self::Addr> {
+        tokio::net::UnixListener::local_addr(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[tokio::test]
+    async fn serve_duplex() {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(server, Arc::new(cdc_server())));
+
+        let devices = UsbIpClient::new(client).list_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_unix_socket() {
+        let path = std::env::temp_dir().join(format!("usbip-test-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let handle = cdc_server().serve_listener(listener).unwrap();
+        assert_eq!(handle.local_addr().as_pathname(), Some(path.as_path()));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let device = UsbIpClient::new(stream).import("0").await.unwrap();
+        assert_eq!(device.bulk_out(0x02, b"hello").await.unwrap(), 5);
+
+        handle.shutdown().await;
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,491 @@
+//! Metrics of URBs handled by a server
+use super::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Instant;
+
+/// Time a metrics client has to send its request
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum length of the request line and headers of a metrics request
+const MAX_METRICS_REQUEST_LENGTH: usize = 8 * 1024;
+/// Maximum number of metrics requests served concurrently
+const MAX_METRICS_REQUESTS: usize = 16;
+
+/// Upper bounds in seconds of the buckets of [Histogram]
+pub const LATENCY_BUCKETS: [f64; 10] =
+    [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Distribution of handler latency
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    /// Number of samples in each bucket of [LATENCY_BUCKETS], and above the last one
+    pub counts: [u64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of all samples
+    pub sum: Duration,
+    /// Number of samples
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; LATENCY_BUCKETS.len() + 1],
+            sum: Duration::ZERO,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, sample: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| sample.as_secs_f64() <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += sample;
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Counters of one endpoint
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EndpointMetrics {
+    /// URBs submitted by clients
+    pub submitted: u64,
+    /// URBs completed, including those with an error status and those rejected on submission
+    pub completed: u64,
+    /// URBs cancelled by USBIP_CMD_UNLINK before completion
+    pub unlinked: u64,
+    /// Completed URBs with an error, by status
+    pub errors: BTreeMap<i32, u64>,
+    /// Bytes transferred to clients
+    pub bytes_in: u64,
+    /// Bytes transferred from clients
+    pub bytes_out: u64,
+    /// Time spent in handlers for completed URBs
+    pub latency: Histogram,
+}
+
+impl EndpointMetrics {
+    fn merge(&mut self, other: &EndpointMetrics) {
+        self.submitted += other.submitted;
+        self.completed += other.completed;
+        self.unlinked += other.unlinked;
+        for (status, count) in &other.errors {
+            *self.errors.entry(*status).or_default() += count;
+        }
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.latency.merge(&other.latency);
+    }
+}
+
+/// Counters of one device, by endpoint address
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceMetrics {
+    pub endpoints: BTreeMap<u8, EndpointMetrics>,
+}
+
+impl DeviceMetrics {
+    /// Counters of all endpoints together
+    pub fn total(&self) -> EndpointMetrics {
+        let mut total = EndpointMetrics::default();
+        for endpoint in self.endpoints.values() {
+            total.merge(endpoint);
+        }
+        total
+    }
+}
+
+/// Metrics of a server at one point in time, see [UsbIpServer::metrics]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Counters by bus id, including devices removed since
+    pub devices: BTreeMap<String, DeviceMetrics>,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MetricsSnapshot {
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let endpoints: Vec<(String, &EndpointMetrics)> = self
+            .devices
+            .iter()
+            .flat_map(|(bus_id, device)| {
+                device.endpoints.iter().map(move |(ep, metrics)| {
+                    (
+                        format!(
+                            "bus_id=\"{}\",endpoint=\"0x{:02x}\"",
+                            escape_label(bus_id),
+                            ep
+                        ),
+                        metrics,
+                    )
+                })
+            })
+            .collect();
+
+        let mut out = String::new();
+        type Counter = (&'static str, &'static str, fn(&EndpointMetrics) -> u64);
+        let counters: [Counter; 3] = [
+            (
+                "usbip_urbs_submitted_total",
+                "URBs submitted by clients",
+                |m| m.submitted,
+            ),
+            ("usbip_urbs_completed_total", "URBs completed", |m| {
+                m.completed
+            }),
+            (
+                "usbip_urbs_unlinked_total",
+                "URBs cancelled before completion",
+                |m| m.unlinked,
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (labels, metrics) in &endpoints {
+                writeln!(out, "{}{{{}}} {}", name, labels, value(metrics)).unwrap();
+            }
+        }
+
+        let name = "usbip_urb_errors_total";
+        writeln!(
+            out,
+            "# HELP {} URBs completed with an error, by status",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        for (labels, metrics) in &endpoints {
+            for (status, count) in &metrics.errors {
+                writeln!(
+                    out,
+                    "{}{{{},status=\"{}\"}} {}",
+                    name, labels, status, count
+                )
+                .unwrap();
+            }
+        }
+
+        let name = "usbip_transfer_bytes_total";
+        writeln!(out, "# HELP {} Bytes transferred, by direction", name).unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        for (labels, metrics) in &endpoints {
+            writeln!(
+                out,
+                "{}{{{},direction=\"in\"}} {}",
+                name, labels, metrics.bytes_in
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}{{{},direction=\"out\"}} {}",
+                name, labels, metrics.bytes_out
+            )
+            .unwrap();
+        }
+
+        let name = "usbip_handler_latency_seconds";
+        writeln!(out, "# HELP {} Time spent in handlers", name).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (labels, metrics) in &endpoints {
+            let latency = &metrics.latency;
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.counts) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, bound, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name, labels, latency.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}_sum{{{}}} {}",
+                name,
+                labels,
+                latency.sum.as_secs_f64()
+            )
+            .unwrap();
+            writeln!(out, "{}_count{{{}}} {}", name, labels, latency.count).unwrap();
+        }
+        out
+    }
+}
+
+/// Metrics collected by a server
+#[derive(Default)]
+pub(crate) struct Metrics {
+    devices: Mutex<BTreeMap<String, DeviceMetrics>>,
+}
+
+impl Metrics {
+    fn update(&self, bus_id: &str, ep: u8, f: impl FnOnce(&mut EndpointMetrics)) {
+        let mut devices = self.devices.lock().unwrap();
+        let device = match devices.get_mut(bus_id) {
+            Some(device) => device,
+            None => devices.entry(bus_id.to_string()).or_default(),
+        };
+        f(device.endpoints.entry(ep).or_default());
+    }
+
+    pub(crate) fn submitted(&self, bus_id: &str, ep: u8) {
+        self.update(bus_id, ep, |m| m.submitted += 1);
+    }
+
+    pub(crate) fn unlinked(&self, bus_id: &str, ep: u8) {
+        self.update(bus_id, ep, |m| m.unlinked += 1);
+    }
+
+    /// A submitted URB replied with `status` without reaching a handler
+    pub(crate) fn rejected(&self, bus_id: &str, ep: u8, status: i32) {
+        self.update(bus_id, ep, |m| {
+            m.completed += 1;
+            *m.errors.entry(status).or_default() += 1;
+        });
+    }
+
+    pub(crate) fn completed(&self, bus_id: &str, ep: u8, ret: &RetSubmit, started: Instant) {
+        let latency = started.elapsed();
+        self.update(bus_id, ep, |m| {
+            m.completed += 1;
+            if ret.status != 0 {
+                *m.errors.entry(ret.status).or_default() += 1;
+            }
+            // short and failed transfers may still have moved data
+            if ret.header.direction == USBIP_DIR_IN {
+                m.bytes_in += ret.actual_length as u64;
+            } else {
+                m.bytes_out += ret.actual_length as u64;
+            }
+            m.latency.observe(latency);
+        });
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            devices: self.devices.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl UsbIpServer {
+    /// Current metrics of all devices
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Serve metrics in the Prometheus text format over HTTP on `addr`, until the server shuts down
+    ///
+    /// Every request gets the metrics regardless of its path, and a few requests are served at
+    /// a time. Returns the bound address.
+    pub async fn serve_metrics(self: &Arc<Self>, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut shutdown = server.shutdown.subscribe();
+            let mut requests = tokio::task::JoinSet::new();
+            loop {
+                // further clients wait in the backlog of the listener
+                let accepting = requests.len() < MAX_METRICS_REQUESTS;
+                tokio::select! {
+                    res = listener.accept(), if accepting => match res {
+                        Ok((socket, _addr)) => {
+                            let body = server.metrics().to_prometheus();
+                            let request =
+                                serve_metrics_request(socket, body, METRICS_REQUEST_TIMEOUT);
+                            requests.spawn(async move {
+                                if let Err(err) = request.await {
+                                    debug!("Metrics request failed: {}", err);
+                                }
+                            });
+                        }
+                        Err(err) => warn!("Got error {:?}", err),
+                    },
+                    Some(_) = requests.join_next() => {}
+                    _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+async fn serve_metrics_request(
+    mut socket: tokio::net::TcpStream,
+    body: String,
+    timeout: Duration,
+) -> Result<()> {
+    // skip the request up to the empty line
+    tokio::time::timeout(timeout, skip_request(&mut socket))
+        .await
+        .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+async fn skip_request(socket: &mut tokio::net::TcpStream) -> Result<()> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_METRICS_REQUEST_LENGTH {
+            return Err(std::io::Error::from(ErrorKind::InvalidData));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[tokio::test]
+    async fn urb_metrics() {
+        let handle = cdc_server()
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let device = UsbIpClient::connect(handle.local_addr())
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+
+        // GetDescriptor to Device
+        let get_device_descriptor = SetupPacket {
+            request_type: 0x80,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Device as u16) << 8,
+            index: 0,
+            length: 0x40,
+        };
+        device.control_in(get_device_descriptor).await.unwrap();
+        // GetDescriptor to OTG is unsupported
+        let res = device
+            .control_in(SetupPacket {
+                value: (DescriptorType::OTG as u16) << 8,
+                ..get_device_descriptor
+            })
+            .await;
+        assert!(res.is_err());
+        device.bulk_out(0x02, b"hello").await.unwrap();
+        // rejected on submission
+        assert!(device.bulk_in(0x83, 8).await.is_err());
+
+        let metrics = handle.server().metrics();
+        let ep0 = &metrics.devices["0"].endpoints[&0x80];
+        assert_eq!(ep0.submitted, 2);
+        assert_eq!(ep0.completed, 2);
+        assert_eq!(ep0.errors[&-EPIPE], 1);
+        assert_eq!(ep0.bytes_in, 0x12);
+        assert_eq!(ep0.latency.count, 2);
+        let ep2 = &metrics.devices["0"].endpoints[&0x02];
+        assert_eq!(ep2.bytes_out, 5);
+        let ep3 = &metrics.devices["0"].endpoints[&0x83];
+        assert_eq!((ep3.submitted, ep3.completed), (1, 1));
+        assert_eq!(ep3.errors[&-EPIPE], 1);
+        assert_eq!(metrics.devices["0"].total().submitted, 4);
+
+        let addr = handle
+            .server()
+            .serve_metrics("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut http = tokio::net::TcpStream::connect(addr).await.unwrap();
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("usbip_urbs_submitted_total{bus_id=\"0\",endpoint=\"0x80\"} 2\n"));
+        assert!(response
+            .contains("usbip_urb_errors_total{bus_id=\"0\",endpoint=\"0x80\",status=\"-32\"} 1\n"));
+        assert!(response.contains(
+            "usbip_transfer_bytes_total{bus_id=\"0\",endpoint=\"0x02\",direction=\"out\"} 5\n"
+        ));
+        assert!(response
+            .contains("usbip_handler_latency_seconds_count{bus_id=\"0\",endpoint=\"0x80\"} 2\n"));
+
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn failed_transfer_bytes() {
+        let metrics = Metrics::default();
+        let ret = RetSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_SUBMIT,
+                direction: USBIP_DIR_IN,
+                ep: 1,
+                ..Default::default()
+            },
+            status: -EPIPE,
+            actual_length: 3,
+            ..RetSubmit::default()
+        };
+        metrics.completed("0", 0x81, &ret, Instant::now());
+        let ep1 = &metrics.snapshot().devices["0"].endpoints[&0x81];
+        assert_eq!(ep1.errors[&-EPIPE], 1);
+        assert_eq!(ep1.bytes_in, 3);
+    }
+
+    #[tokio::test]
+    async fn bad_metrics_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // never sends a request
+        let _silent = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let res = serve_metrics_request(socket, String::new(), Duration::from_millis(100)).await;
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+
+        // headers never end
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn(serve_metrics_request(
+            socket,
+            String::new(),
+            Duration::from_secs(10),
+        ));
+        let header = vec![b'a'; MAX_METRICS_REQUEST_LENGTH + 1];
+        client.write_all(&header).await.ok();
+        let res = server.await.unwrap();
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
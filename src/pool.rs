@@ -0,0 +1,57 @@
+//! Reuse of transfer buffers
+use super::*;
+
+/// Max number of buffers kept by a [BufferPool]
+const MAX_POOLED_BUFFERS: usize = 64;
+/// Larger buffers are freed instead of kept
+const MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+
+/// Buffers of OUT data and encoded replies, reused across URBs of a connection
+///
+/// IN data is allocated by the handlers, its buffer joins the pool once written.
+#[derive(Default)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// An empty buffer, with capacity left from a previous use if any
+    pub(crate) fn get(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Return a buffer for reuse
+    pub(crate) fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_buffers() {
+        let pool = BufferPool::default();
+        assert_eq!(pool.get().capacity(), 0);
+
+        let mut buffer = pool.get();
+        buffer.extend_from_slice(&[0xaa; 100]);
+        let ptr = buffer.as_ptr();
+        pool.put(buffer);
+        let buffer = pool.get();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+
+        // too large to keep
+        pool.put(vec![0; MAX_POOLED_CAPACITY + 1]);
+        assert_eq!(pool.get().capacity(), 0);
+    }
+}
@@ -0,0 +1,1055 @@
+//! Encoding and decoding of USB/IP messages
+//!
+//! See [USB/IP protocol](https://docs.kernel.org/usb/usbip_protocol.html)
+use super::*;
+
+/// Version of USB/IP protocol
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// Code of OP_REQ_DEVLIST
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+/// Code of OP_REP_DEVLIST
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+/// Code of OP_REQ_IMPORT
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+/// Code of OP_REP_IMPORT
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+/// Status of OP_REP_*: request completed successfully
+pub const ST_OK: u32 = 0x00;
+/// Status of OP_REP_*: request failed
+pub const ST_NA: u32 = 0x01;
+/// Status of OP_REP_*: device is busy, e.g. imported by another client
+pub const ST_DEV_BUSY: u32 = 0x02;
+/// Status of OP_REP_*: device is in an error state
+pub const ST_DEV_ERR: u32 = 0x03;
+/// Status of OP_REP_*: device not found
+pub const ST_NODEV: u32 = 0x04;
+/// Status of OP_REP_*: unexpected response, e.g. unsupported version or op code
+pub const ST_ERROR: u32 = 0x05;
+
+/// Command of USBIP_CMD_SUBMIT
+pub const USBIP_CMD_SUBMIT: u32 = 0x1;
+/// Command of USBIP_CMD_UNLINK
+pub const USBIP_CMD_UNLINK: u32 = 0x2;
+/// Command of USBIP_RET_SUBMIT
+pub const USBIP_RET_SUBMIT: u32 = 0x3;
+/// Command of USBIP_RET_UNLINK
+pub const USBIP_RET_UNLINK: u32 = 0x4;
+
+/// Direction OUT in [UsbIpHeaderBasic]
+pub const USBIP_DIR_OUT: u32 = 0x0;
+/// Direction IN in [UsbIpHeaderBasic]
+pub const USBIP_DIR_IN: u32 = 0x1;
+
+/// Transfer flag of [CmdSubmit]: a short IN transfer completes with -EREMOTEIO
+pub const USBIP_URB_SHORT_NOT_OK: u32 = 0x0001;
+/// Transfer flag of [CmdSubmit]: schedule isochronous packets as soon as possible
+pub const USBIP_URB_ISO_ASAP: u32 = 0x0002;
+/// Transfer flag of [CmdSubmit]: end an OUT transfer of full packets with a zero length packet
+pub const USBIP_URB_ZERO_PACKET: u32 = 0x0040;
+/// Transfer flag of [CmdSubmit]: no completion interrupt is needed
+pub const USBIP_URB_NO_INTERRUPT: u32 = 0x0080;
+/// Transfer flag of [CmdSubmit]: the URB is IN
+pub const USBIP_URB_DIR_IN: u32 = 0x0200;
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+async fn read_op_common<T: AsyncReadExt + Unpin>(socket: &mut T, code: u16) -> Result<u16> {
+    let version = socket.read_u16().await?;
+    let actual = socket.read_u16().await?;
+    if actual != code {
+        return Err(invalid_data(format!(
+            "expected op code {:04x}, got {:04x}",
+            code, actual
+        )));
+    }
+    Ok(version)
+}
+
+async fn read_command<T: AsyncReadExt + Unpin>(socket: &mut T, command: u32) -> Result<()> {
+    let actual = socket.read_u32().await?;
+    if actual != command {
+        return Err(invalid_data(format!(
+            "expected command {:x}, got {:x}",
+            command, actual
+        )));
+    }
+    Ok(())
+}
+
+/// Device description in OP_REP_DEVLIST and OP_REP_IMPORT
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    pub path: String,
+    pub bus_id: String,
+    pub bus_num: u32,
+    pub dev_num: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_bcd: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+}
+
+impl UsbDeviceInfo {
+    /// Size on the wire
+    pub const SIZE: usize = 0x138;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        Ok(Self {
+            path: socket_read_fixed_string(socket, 256).await?,
+            bus_id: socket_read_fixed_string(socket, 32).await?,
+            bus_num: socket.read_u32().await?,
+            dev_num: socket.read_u32().await?,
+            speed: socket.read_u32().await?,
+            vendor_id: socket.read_u16().await?,
+            product_id: socket.read_u16().await?,
+            device_bcd: socket.read_u16().await?,
+            device_class: socket.read_u8().await?,
+            device_subclass: socket.read_u8().await?,
+            device_protocol: socket.read_u8().await?,
+            configuration_value: socket.read_u8().await?,
+            num_configurations: socket.read_u8().await?,
+            num_interfaces: socket.read_u8().await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket_write_fixed_string(socket, &self.path, 256).await?;
+        socket_write_fixed_string(socket, &self.bus_id, 32).await?;
+        socket.write_u32(self.bus_num).await?;
+        socket.write_u32(self.dev_num).await?;
+        socket.write_u32(self.speed).await?;
+        socket.write_u16(self.vendor_id).await?;
+        socket.write_u16(self.product_id).await?;
+        socket.write_u16(self.device_bcd).await?;
+        socket.write_u8(self.device_class).await?;
+        socket.write_u8(self.device_subclass).await?;
+        socket.write_u8(self.device_protocol).await?;
+        socket.write_u8(self.configuration_value).await?;
+        socket.write_u8(self.num_configurations).await?;
+        socket.write_u8(self.num_interfaces).await?;
+        Ok(())
+    }
+}
+
+/// Interface description in OP_REP_DEVLIST
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsbInterfaceInfo {
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+}
+
+impl UsbInterfaceInfo {
+    /// Size on the wire
+    pub const SIZE: usize = 0x4;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let res = Self {
+            interface_class: socket.read_u8().await?,
+            interface_subclass: socket.read_u8().await?,
+            interface_protocol: socket.read_u8().await?,
+        };
+        // padding
+        socket.read_u8().await?;
+        Ok(res)
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u8(self.interface_class).await?;
+        socket.write_u8(self.interface_subclass).await?;
+        socket.write_u8(self.interface_protocol).await?;
+        // padding
+        socket.write_u8(0).await?;
+        Ok(())
+    }
+}
+
+/// Header common to OP_REQ_* and OP_REP_*, e.g. the error reply to an unknown op code
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpCommon {
+    pub version: u16,
+    pub code: u16,
+    pub status: u32,
+}
+
+impl OpCommon {
+    /// Size on the wire
+    pub const SIZE: usize = 0x8;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        Ok(Self {
+            version: socket.read_u16().await?,
+            code: socket.read_u16().await?,
+            status: socket.read_u32().await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u16(self.version).await?;
+        socket.write_u16(self.code).await?;
+        socket.write_u32(self.status).await?;
+        Ok(())
+    }
+}
+
+/// OP_REQ_DEVLIST: list exported devices
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpReqDevlist {
+    pub version: u16,
+    pub status: u32,
+}
+
+impl Default for OpReqDevlist {
+    fn default() -> Self {
+        Self {
+            version: USBIP_VERSION,
+            status: 0,
+        }
+    }
+}
+
+impl OpReqDevlist {
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let version = read_op_common(socket, OP_REQ_DEVLIST).await?;
+        Self::read_body(socket, version).await
+    }
+
+    async fn read_body<T: AsyncReadExt + Unpin>(socket: &mut T, version: u16) -> Result<Self> {
+        Ok(Self {
+            version,
+            status: socket.read_u32().await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u16(self.version).await?;
+        socket.write_u16(OP_REQ_DEVLIST).await?;
+        socket.write_u32(self.status).await?;
+        Ok(())
+    }
+}
+
+/// OP_REP_DEVLIST: exported devices with their interfaces
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpRepDevlist {
+    pub version: u16,
+    pub status: u32,
+    pub devices: Vec<(UsbDeviceInfo, Vec<UsbInterfaceInfo>)>,
+}
+
+impl Default for OpRepDevlist {
+    fn default() -> Self {
+        Self {
+            version: USBIP_VERSION,
+            status: 0,
+            devices: vec![],
+        }
+    }
+}
+
+impl OpRepDevlist {
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let version = read_op_common(socket, OP_REP_DEVLIST).await?;
+        let status = socket.read_u32().await?;
+        let mut devices = vec![];
+        if status != ST_OK {
+            // no device list follows an error
+            return Ok(Self {
+                version,
+                status,
+                devices,
+            });
+        }
+        let num_devices = socket.read_u32().await?;
+        for _ in 0..num_devices {
+            let device = UsbDeviceInfo::read(socket).await?;
+            let mut interfaces = vec![];
+            for _ in 0..device.num_interfaces {
+                interfaces.push(UsbInterfaceInfo::read(socket).await?);
+            }
+            devices.push((device, interfaces));
+        }
+        Ok(Self {
+            version,
+            status,
+            devices,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u16(self.version).await?;
+        socket.write_u16(OP_REP_DEVLIST).await?;
+        socket.write_u32(self.status).await?;
+        if self.status != ST_OK {
+            return Ok(());
+        }
+        socket.write_u32(self.devices.len() as u32).await?;
+        for (device, interfaces) in &self.devices {
+            device.write(socket).await?;
+            for interface in interfaces {
+                interface.write(socket).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// OP_REQ_IMPORT: attach to a device by its bus id
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpReqImport {
+    pub version: u16,
+    pub status: u32,
+    pub bus_id: String,
+}
+
+impl Default for OpReqImport {
+    fn default() -> Self {
+        Self {
+            version: USBIP_VERSION,
+            status: 0,
+            bus_id: String::new(),
+        }
+    }
+}
+
+impl OpReqImport {
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let version = read_op_common(socket, OP_REQ_IMPORT).await?;
+        Self::read_body(socket, version).await
+    }
+
+    async fn read_body<T: AsyncReadExt + Unpin>(socket: &mut T, version: u16) -> Result<Self> {
+        Ok(Self {
+            version,
+            status: socket.read_u32().await?,
+            bus_id: socket_read_fixed_string(socket, 32).await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u16(self.version).await?;
+        socket.write_u16(OP_REQ_IMPORT).await?;
+        socket.write_u32(self.status).await?;
+        socket_write_fixed_string(socket, &self.bus_id, 32).await?;
+        Ok(())
+    }
+}
+
+/// OP_REP_IMPORT: the imported device if status is zero
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpRepImport {
+    pub version: u16,
+    pub status: u32,
+    pub device: Option<UsbDeviceInfo>,
+}
+
+impl Default for OpRepImport {
+    fn default() -> Self {
+        Self {
+            version: USBIP_VERSION,
+            status: 0,
+            device: None,
+        }
+    }
+}
+
+impl OpRepImport {
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let version = read_op_common(socket, OP_REP_IMPORT).await?;
+        let status = socket.read_u32().await?;
+        let device = if status == ST_OK {
+            Some(UsbDeviceInfo::read(socket).await?)
+        } else {
+            None
+        };
+        Ok(Self {
+            version,
+            status,
+            device,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        socket.write_u16(self.version).await?;
+        socket.write_u16(OP_REP_IMPORT).await?;
+        socket.write_u32(self.status).await?;
+        if let Some(device) = &self.device {
+            device.write(socket).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Header common to USBIP_CMD_* and USBIP_RET_*
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsbIpHeaderBasic {
+    pub command: u32,
+    pub seq_num: u32,
+    pub dev_id: u32,
+    /// [USBIP_DIR_OUT] or [USBIP_DIR_IN]
+    pub direction: u32,
+    /// Endpoint number, without direction bit
+    pub ep: u32,
+}
+
+impl UsbIpHeaderBasic {
+    /// Size on the wire
+    pub const SIZE: usize = 0x14;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        let command = socket.read_u32().await?;
+        Self::read_body(socket, command).await
+    }
+
+    async fn read_body<T: AsyncReadExt + Unpin>(socket: &mut T, command: u32) -> Result<Self> {
+        Ok(Self {
+            command,
+            seq_num: socket.read_u32().await?,
+            dev_id: socket.read_u32().await?,
+            direction: socket.read_u32().await?,
+            ep: socket.read_u32().await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        self.encode(&mut buf);
+        socket.write_all(&buf).await
+    }
+
+    /// Append the wire format to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.command.to_be_bytes());
+        buf.extend_from_slice(&self.seq_num.to_be_bytes());
+        buf.extend_from_slice(&self.dev_id.to_be_bytes());
+        buf.extend_from_slice(&self.direction.to_be_bytes());
+        buf.extend_from_slice(&self.ep.to_be_bytes());
+    }
+
+    /// Endpoint address with direction bit
+    pub fn endpoint_address(&self) -> u8 {
+        if self.direction == USBIP_DIR_OUT {
+            self.ep as u8
+        } else {
+            self.ep as u8 | 0x80
+        }
+    }
+}
+
+impl IsoPacketDescriptor {
+    /// Size on the wire
+    pub const SIZE: usize = 0x10;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        Ok(Self {
+            offset: socket.read_u32().await?,
+            length: socket.read_u32().await?,
+            actual_length: socket.read_u32().await?,
+            status: socket.read_i32().await?,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        self.encode(&mut buf);
+        socket.write_all(&buf).await
+    }
+
+    /// Append the wire format to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        buf.extend_from_slice(&self.actual_length.to_be_bytes());
+        buf.extend_from_slice(&self.status.to_be_bytes());
+    }
+}
+
+/// Read `length` bytes of a transfer buffer into `data`
+///
+/// The buffer grows as data arrives, so a bogus length in a short message does not allocate it all
+async fn read_transfer_buffer<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    length: u32,
+    mut data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    data.clear();
+    socket.take(length as u64).read_to_end(&mut data).await?;
+    if data.len() != length as usize {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(data)
+}
+
+/// Discard `length` bytes of a transfer buffer
+async fn skip_transfer_buffer<T: AsyncReadExt + Unpin>(socket: &mut T, length: u32) -> Result<()> {
+    let skipped = tokio::io::copy(&mut socket.take(length as u64), &mut tokio::io::sink()).await?;
+    if skipped != length as u64 {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+/// Whether iso packet descriptors follow a URB with `number_of_packets`
+///
+/// Non-isochronous URBs have zero packets, or 0xFFFFFFFF for some clients
+fn has_iso_packets(number_of_packets: u32) -> bool {
+    number_of_packets != 0 && number_of_packets != 0xFFFFFFFF
+}
+
+async fn read_iso_packets<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    number_of_packets: u32,
+) -> Result<Vec<IsoPacketDescriptor>> {
+    let mut packets = vec![];
+    if has_iso_packets(number_of_packets) {
+        if number_of_packets > USBIP_MAX_ISO_PACKETS {
+            return Err(invalid_data(format!(
+                "too many isochronous packets: {}",
+                number_of_packets
+            )));
+        }
+        for _ in 0..number_of_packets {
+            packets.push(IsoPacketDescriptor::read(socket).await?);
+        }
+    }
+    Ok(packets)
+}
+
+/// USBIP_CMD_SUBMIT: submit a URB
+///
+/// The transfer buffer is only present for OUT, iso packet descriptors only for isochronous transfers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CmdSubmit {
+    pub header: UsbIpHeaderBasic,
+    pub transfer_flags: u32,
+    pub transfer_buffer_length: u32,
+    pub start_frame: u32,
+    pub number_of_packets: u32,
+    pub interval: u32,
+    pub setup: [u8; 8],
+    pub transfer_buffer: Vec<u8>,
+    pub iso_packet_descriptor: Vec<IsoPacketDescriptor>,
+}
+
+impl CmdSubmit {
+    /// Size on the wire without transfer buffer and iso packet descriptors
+    pub const SIZE: usize = 0x30;
+
+    /// Whether all of `flags`, a combination of `USBIP_URB_*`, are set in the transfer flags
+    pub fn has_flags(&self, flags: u32) -> bool {
+        self.transfer_flags & flags == flags
+    }
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        read_command(socket, USBIP_CMD_SUBMIT).await?;
+        let header = UsbIpHeaderBasic::read_body(socket, USBIP_CMD_SUBMIT).await?;
+        Self::read_body(socket, header, u32::MAX, Vec::new).await
+    }
+
+    async fn read_body<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        header: UsbIpHeaderBasic,
+        max_transfer_length: u32,
+        buffer: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Self> {
+        let transfer_flags = socket.read_u32().await?;
+        let transfer_buffer_length = socket.read_u32().await?;
+        let start_frame = socket.read_u32().await?;
+        let number_of_packets = socket.read_u32().await?;
+        let interval = socket.read_u32().await?;
+        let mut setup = [0u8; 8];
+        socket.read_exact(&mut setup).await?;
+        let transfer_buffer = if header.direction != USBIP_DIR_OUT {
+            vec![]
+        } else if transfer_buffer_length > max_transfer_length {
+            skip_transfer_buffer(socket, transfer_buffer_length).await?;
+            vec![]
+        } else {
+            read_transfer_buffer(socket, transfer_buffer_length, buffer()).await?
+        };
+        let iso_packet_descriptor = read_iso_packets(socket, number_of_packets).await?;
+        Ok(Self {
+            header,
+            transfer_flags,
+            transfer_buffer_length,
+            start_frame,
+            number_of_packets,
+            interval,
+            setup,
+            transfer_buffer,
+            iso_packet_descriptor,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        let mut buf = Vec::with_capacity(
+            Self::SIZE
+                + self.transfer_buffer.len()
+                + self.iso_packet_descriptor.len() * IsoPacketDescriptor::SIZE,
+        );
+        self.header.encode(&mut buf);
+        buf.extend_from_slice(&self.transfer_flags.to_be_bytes());
+        buf.extend_from_slice(&self.transfer_buffer_length.to_be_bytes());
+        buf.extend_from_slice(&self.start_frame.to_be_bytes());
+        buf.extend_from_slice(&self.number_of_packets.to_be_bytes());
+        buf.extend_from_slice(&self.interval.to_be_bytes());
+        buf.extend_from_slice(&self.setup);
+        buf.extend_from_slice(&self.transfer_buffer);
+        for packet in &self.iso_packet_descriptor {
+            packet.encode(&mut buf);
+        }
+        socket.write_all(&buf).await
+    }
+}
+
+/// USBIP_RET_SUBMIT: completion of a URB
+///
+/// The transfer buffer is only present for IN, iso packet descriptors only for isochronous transfers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetSubmit {
+    pub header: UsbIpHeaderBasic,
+    /// Zero or a negative Linux errno
+    pub status: i32,
+    pub actual_length: u32,
+    pub start_frame: u32,
+    pub number_of_packets: u32,
+    pub error_count: u32,
+    pub setup: [u8; 8],
+    pub transfer_buffer: Vec<u8>,
+    pub iso_packet_descriptor: Vec<IsoPacketDescriptor>,
+}
+
+impl RetSubmit {
+    /// Size on the wire without transfer buffer and iso packet descriptors
+    pub const SIZE: usize = 0x30;
+
+    /// Read a [RetSubmit], `direction` is the one of the submitted URB
+    ///
+    /// Some servers set the direction in the header to zero, so it is not used here
+    pub async fn read<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        direction: Direction,
+    ) -> Result<Self> {
+        read_command(socket, USBIP_RET_SUBMIT).await?;
+        let header = UsbIpHeaderBasic::read_body(socket, USBIP_RET_SUBMIT).await?;
+        Self::read_body(socket, header, direction).await
+    }
+
+    /// Read the rest of a [RetSubmit] after its header
+    pub async fn read_body<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        header: UsbIpHeaderBasic,
+        direction: Direction,
+    ) -> Result<Self> {
+        let status = socket.read_i32().await?;
+        let actual_length = socket.read_u32().await?;
+        let start_frame = socket.read_u32().await?;
+        let number_of_packets = socket.read_u32().await?;
+        let error_count = socket.read_u32().await?;
+        let mut setup = [0u8; 8];
+        socket.read_exact(&mut setup).await?;
+        let transfer_buffer = if let Direction::In = direction {
+            read_transfer_buffer(socket, actual_length, vec![]).await?
+        } else {
+            vec![]
+        };
+        let iso_packet_descriptor = read_iso_packets(socket, number_of_packets).await?;
+        Ok(Self {
+            header,
+            status,
+            actual_length,
+            start_frame,
+            number_of_packets,
+            error_count,
+            setup,
+            transfer_buffer,
+            iso_packet_descriptor,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        let mut header = Vec::with_capacity(Self::SIZE);
+        self.encode_header(&mut header);
+        socket.write_all(&header).await?;
+        socket.write_all(&self.transfer_buffer).await?;
+        let mut iso_packets = vec![];
+        self.encode_iso_packets(&mut iso_packets);
+        socket.write_all(&iso_packets).await
+    }
+
+    /// Append the wire format of the fixed size part to `buf`
+    pub fn encode_header(&self, buf: &mut Vec<u8>) {
+        self.header.encode(buf);
+        buf.extend_from_slice(&self.status.to_be_bytes());
+        buf.extend_from_slice(&self.actual_length.to_be_bytes());
+        buf.extend_from_slice(&self.start_frame.to_be_bytes());
+        buf.extend_from_slice(&self.number_of_packets.to_be_bytes());
+        buf.extend_from_slice(&self.error_count.to_be_bytes());
+        buf.extend_from_slice(&self.setup);
+    }
+
+    /// Append the wire format of the iso packet descriptors, which follow the transfer buffer, to `buf`
+    pub fn encode_iso_packets(&self, buf: &mut Vec<u8>) {
+        for packet in &self.iso_packet_descriptor {
+            packet.encode(buf);
+        }
+    }
+}
+
+/// USBIP_CMD_UNLINK: cancel a submitted URB
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CmdUnlink {
+    pub header: UsbIpHeaderBasic,
+    /// seq_num of the URB to cancel
+    pub unlink_seq_num: u32,
+}
+
+impl CmdUnlink {
+    /// Size on the wire
+    pub const SIZE: usize = 0x30;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        read_command(socket, USBIP_CMD_UNLINK).await?;
+        let header = UsbIpHeaderBasic::read_body(socket, USBIP_CMD_UNLINK).await?;
+        Self::read_body(socket, header).await
+    }
+
+    async fn read_body<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        header: UsbIpHeaderBasic,
+    ) -> Result<Self> {
+        let unlink_seq_num = socket.read_u32().await?;
+        // 24 bytes of struct padding
+        let mut padding = [0u8; 6 * 4];
+        socket.read_exact(&mut padding).await?;
+        Ok(Self {
+            header,
+            unlink_seq_num,
+        })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        self.header.write(socket).await?;
+        socket.write_u32(self.unlink_seq_num).await?;
+        socket.write_all(&[0u8; 6 * 4]).await?;
+        Ok(())
+    }
+}
+
+/// USBIP_RET_UNLINK: result of a USBIP_CMD_UNLINK
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetUnlink {
+    pub header: UsbIpHeaderBasic,
+    /// -ECONNRESET if the URB was cancelled, zero if it had completed already
+    pub status: i32,
+}
+
+impl RetUnlink {
+    /// Size on the wire
+    pub const SIZE: usize = 0x30;
+
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        read_command(socket, USBIP_RET_UNLINK).await?;
+        let header = UsbIpHeaderBasic::read_body(socket, USBIP_RET_UNLINK).await?;
+        Self::read_body(socket, header).await
+    }
+
+    /// Read the rest of a [RetUnlink] after its header
+    pub async fn read_body<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        header: UsbIpHeaderBasic,
+    ) -> Result<Self> {
+        let status = socket.read_i32().await?;
+        // 24 bytes of struct padding
+        let mut padding = [0u8; 6 * 4];
+        socket.read_exact(&mut padding).await?;
+        Ok(Self { header, status })
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        self.header.write(socket).await?;
+        socket.write_i32(self.status).await?;
+        socket.write_all(&[0u8; 6 * 4]).await?;
+        Ok(())
+    }
+}
+
+/// A message sent by a USB/IP client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UsbIpCommand {
+    OpReqDevlist(OpReqDevlist),
+    OpReqImport(OpReqImport),
+    CmdSubmit(CmdSubmit),
+    CmdUnlink(CmdUnlink),
+    /// An OP_REQ_* with an unsupported op code, its body is not read
+    OpReqUnknown(OpCommon),
+}
+
+impl UsbIpCommand {
+    /// Read a message of any kind
+    ///
+    /// Unknown op codes are returned as [UsbIpCommand::OpReqUnknown], other unknown commands are reported as [ErrorKind::InvalidData]
+    pub async fn read<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<Self> {
+        Self::read_limited(socket, u32::MAX).await
+    }
+
+    /// Read a message of any kind, without buffering OUT data of URBs longer than `max_transfer_length`
+    ///
+    /// The data of such a URB is discarded: its transfer buffer is empty, and its transfer_buffer_length is kept
+    pub async fn read_limited<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        max_transfer_length: u32,
+    ) -> Result<Self> {
+        Self::read_with_buffer(socket, max_transfer_length, Vec::new).await
+    }
+
+    /// Like [UsbIpCommand::read_limited], reading OUT data of a URB into the buffer returned by `buffer`
+    ///
+    /// `buffer` is only called for OUT data that is kept.
+    pub(crate) async fn read_with_buffer<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        max_transfer_length: u32,
+        buffer: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Self> {
+        let mut command = [0u8; 4];
+        socket.read_exact(&mut command).await?;
+        let version = u16::from_be_bytes([command[0], command[1]]);
+        match u16::from_be_bytes([command[2], command[3]]) {
+            OP_REQ_DEVLIST => Ok(UsbIpCommand::OpReqDevlist(
+                OpReqDevlist::read_body(socket, version).await?,
+            )),
+            OP_REQ_IMPORT => Ok(UsbIpCommand::OpReqImport(
+                OpReqImport::read_body(socket, version).await?,
+            )),
+            code if code & 0x8000 != 0 => Ok(UsbIpCommand::OpReqUnknown(OpCommon {
+                version,
+                code,
+                status: socket.read_u32().await?,
+            })),
+            _ => match u32::from_be_bytes(command) {
+                USBIP_CMD_SUBMIT => {
+                    let header = UsbIpHeaderBasic::read_body(socket, USBIP_CMD_SUBMIT).await?;
+                    Ok(UsbIpCommand::CmdSubmit(
+                        CmdSubmit::read_body(socket, header, max_transfer_length, buffer).await?,
+                    ))
+                }
+                USBIP_CMD_UNLINK => {
+                    let header = UsbIpHeaderBasic::read_body(socket, USBIP_CMD_UNLINK).await?;
+                    Ok(UsbIpCommand::CmdUnlink(
+                        CmdUnlink::read_body(socket, header).await?,
+                    ))
+                }
+                _ => Err(invalid_data(format!("unknown command {:02x?}", command))),
+            },
+        }
+    }
+
+    pub async fn write<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
+        match self {
+            UsbIpCommand::OpReqDevlist(op) => op.write(socket).await,
+            UsbIpCommand::OpReqImport(op) => op.write(socket).await,
+            UsbIpCommand::CmdSubmit(cmd) => cmd.write(socket).await,
+            UsbIpCommand::CmdUnlink(cmd) => cmd.write(socket).await,
+            UsbIpCommand::OpReqUnknown(op) => op.write(socket).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(command: UsbIpCommand) -> usize {
+        let mut buf = vec![];
+        command.write(&mut buf).await.unwrap();
+        let mut slice = buf.as_slice();
+        assert_eq!(UsbIpCommand::read(&mut slice).await.unwrap(), command);
+        assert!(slice.is_empty());
+        buf.len()
+    }
+
+    #[tokio::test]
+    async fn op_req_round_trip() {
+        let len = round_trip(UsbIpCommand::OpReqDevlist(OpReqDevlist::default())).await;
+        assert_eq!(len, 0x8);
+        let len = round_trip(UsbIpCommand::OpReqImport(OpReqImport {
+            bus_id: "1-1".to_string(),
+            ..OpReqImport::default()
+        }))
+        .await;
+        assert_eq!(len, 0x28);
+    }
+
+    #[tokio::test]
+    async fn cmd_round_trip() {
+        let header = UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT,
+            seq_num: 1,
+            dev_id: 0x10002,
+            direction: USBIP_DIR_OUT,
+            ep: 2,
+        };
+        let len = round_trip(UsbIpCommand::CmdSubmit(CmdSubmit {
+            header,
+            transfer_buffer_length: 4,
+            transfer_buffer: vec![1, 2, 3, 4],
+            ..CmdSubmit::default()
+        }))
+        .await;
+        assert_eq!(len, 0x30 + 4);
+        let len = round_trip(UsbIpCommand::CmdSubmit(CmdSubmit {
+            header: UsbIpHeaderBasic {
+                direction: USBIP_DIR_IN,
+                ..header
+            },
+            transfer_buffer_length: 8,
+            number_of_packets: 2,
+            iso_packet_descriptor: vec![
+                IsoPacketDescriptor {
+                    offset: 0,
+                    length: 4,
+                    ..IsoPacketDescriptor::default()
+                },
+                IsoPacketDescriptor {
+                    offset: 4,
+                    length: 4,
+                    ..IsoPacketDescriptor::default()
+                },
+            ],
+            ..CmdSubmit::default()
+        }))
+        .await;
+        assert_eq!(len, 0x30 + 2 * IsoPacketDescriptor::SIZE);
+        let len = round_trip(UsbIpCommand::CmdUnlink(CmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK,
+                seq_num: 2,
+                ..header
+            },
+            unlink_seq_num: 1,
+        }))
+        .await;
+        assert_eq!(len, CmdUnlink::SIZE);
+    }
+
+    #[tokio::test]
+    async fn rep_round_trip() {
+        let device = UsbDeviceInfo {
+            path: "/sys/device/usbip/0".to_string(),
+            bus_id: "0".to_string(),
+            num_interfaces: 1,
+            ..UsbDeviceInfo::default()
+        };
+        let rep = OpRepDevlist {
+            devices: vec![(device.clone(), vec![UsbInterfaceInfo::default()])],
+            ..OpRepDevlist::default()
+        };
+        let mut buf = vec![];
+        rep.write(&mut buf).await.unwrap();
+        assert_eq!(
+            buf.len(),
+            0xC + UsbDeviceInfo::SIZE + UsbInterfaceInfo::SIZE
+        );
+        assert_eq!(OpRepDevlist::read(&mut buf.as_slice()).await.unwrap(), rep);
+
+        let rep = OpRepImport {
+            device: Some(device),
+            ..OpRepImport::default()
+        };
+        let mut buf = vec![];
+        rep.write(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 0x8 + UsbDeviceInfo::SIZE);
+        assert_eq!(OpRepImport::read(&mut buf.as_slice()).await.unwrap(), rep);
+
+        let ret = RetSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_SUBMIT,
+                seq_num: 1,
+                ..UsbIpHeaderBasic::default()
+            },
+            actual_length: 2,
+            transfer_buffer: vec![0x12, 0x01],
+            ..RetSubmit::default()
+        };
+        let mut buf = vec![];
+        ret.write(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), RetSubmit::SIZE + 2);
+        assert_eq!(
+            RetSubmit::read(&mut buf.as_slice(), Direction::In)
+                .await
+                .unwrap(),
+            ret
+        );
+
+        let ret = RetUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_RET_UNLINK,
+                seq_num: 2,
+                ..UsbIpHeaderBasic::default()
+            },
+            status: -ECONNRESET,
+        };
+        let mut buf = vec![];
+        ret.write(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), RetUnlink::SIZE);
+        assert_eq!(RetUnlink::read(&mut buf.as_slice()).await.unwrap(), ret);
+    }
+
+    #[tokio::test]
+    async fn unknown_command() {
+        let mut input: &[u8] = &[0x00, 0x00, 0x00, 0x05];
+        let err = UsbIpCommand::read(&mut input).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let len = round_trip(UsbIpCommand::OpReqUnknown(OpCommon {
+            version: USBIP_VERSION,
+            code: 0x8006,
+            status: 0,
+        }))
+        .await;
+        assert_eq!(len, OpCommon::SIZE);
+    }
+
+    #[tokio::test]
+    async fn truncated_transfer_buffer() {
+        let mut buf = vec![];
+        CmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT,
+                direction: USBIP_DIR_OUT,
+                ..UsbIpHeaderBasic::default()
+            },
+            transfer_buffer_length: 0xffffffff,
+            transfer_buffer: vec![0x01, 0x02],
+            ..CmdSubmit::default()
+        }
+        .write(&mut buf)
+        .await
+        .unwrap();
+        let err = UsbIpCommand::read(&mut buf.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn op_rep_error() {
+        let rep = OpRepDevlist {
+            status: ST_ERROR,
+            ..OpRepDevlist::default()
+        };
+        let mut buf = vec![];
+        rep.write(&mut buf).await.unwrap();
+        // no device count after an error
+        assert_eq!(buf, [0x01, 0x11, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05]);
+        assert_eq!(OpRepDevlist::read(&mut buf.as_slice()).await.unwrap(), rep);
+    }
+}
@@ -0,0 +1,911 @@
+//! Record the URBs of a device and replay them as a simulated device
+use super::*;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// First line of a recording, followed by the version of the format
+const RECORDING_HEADER: &str = "usbip-recording";
+/// Version of the format of recordings
+const RECORDING_VERSION: u32 = 1;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid_data(line: &str) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid recording line: {}", line),
+    )
+}
+
+/// `key=value` fields of a line in a recording
+struct Fields<'a> {
+    line: &'a str,
+    fields: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Fields<'a> {
+    fn parse(line: &'a str) -> Self {
+        Self {
+            line,
+            fields: line
+                .split_whitespace()
+                .skip(1)
+                .filter_map(|field| field.split_once('='))
+                .collect(),
+        }
+    }
+
+    fn str(&self, key: &str) -> Result<&'a str> {
+        self.fields
+            .get(key)
+            .copied()
+            .ok_or_else(|| invalid_data(self.line))
+    }
+
+    fn num<T: std::str::FromStr>(&self, key: &str) -> Result<T> {
+        self.str(key)?.parse().map_err(|_| invalid_data(self.line))
+    }
+
+    fn hex(&self, key: &str) -> Result<Vec<u8>> {
+        from_hex(self.str(key)?).ok_or_else(|| invalid_data(self.line))
+    }
+}
+
+/// Handler a recorded URB went to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Device,
+    Interface(usize),
+}
+
+/// A URB and its result in a recording
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RecordedUrb {
+    target: Target,
+    ep: u8,
+    setup: [u8; 8],
+    /// Requested length of an isochronous packet
+    iso_length: Option<u32>,
+    out_data: Vec<u8>,
+    in_data: Vec<u8>,
+    /// Zero or a positive Linux errno
+    errno: i32,
+}
+
+impl RecordedUrb {
+    fn to_line(&self) -> String {
+        let target = match self.target {
+            Target::Device => "device".to_string(),
+            Target::Interface(index) => index.to_string(),
+        };
+        let mut line = format!(
+            "urb target={} ep={} setup={} out={} in={} errno={}",
+            target,
+            self.ep,
+            to_hex(&self.setup),
+            to_hex(&self.out_data),
+            to_hex(&self.in_data),
+            self.errno
+        );
+        if let Some(length) = self.iso_length {
+            line += &format!(" iso_length={}", length);
+        }
+        line
+    }
+
+    fn parse(fields: &Fields) -> Result<Self> {
+        let target = match fields.str("target")? {
+            "device" => Target::Device,
+            index => Target::Interface(index.parse().map_err(|_| invalid_data(fields.line))?),
+        };
+        Ok(Self {
+            target,
+            ep: fields.num("ep")?,
+            setup: fields
+                .hex("setup")?
+                .try_into()
+                .map_err(|_| invalid_data(fields.line))?,
+            iso_length: match fields.str("iso_length") {
+                Ok(_) => Some(fields.num("iso_length")?),
+                Err(_) => None,
+            },
+            out_data: fields.hex("out")?,
+            in_data: fields.hex("in")?,
+            errno: fields.num("errno")?,
+        })
+    }
+}
+
+/// Appends URBs to a recording
+struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    fn create(path: &Path, device: &UsbDevice) -> Result<Self> {
+        let mut lines = vec![format!("{} {}", RECORDING_HEADER, RECORDING_VERSION)];
+        lines.push(format!(
+            "device speed={} vendor={} product={} bcd={}.{}.{} usb={}.{}.{} class={} subclass={} protocol={} active_configuration={} ep0_max_packet_size={} string_manufacturer={} string_product={} string_serial={}",
+            device.speed,
+            device.vendor_id,
+            device.product_id,
+            device.device_bcd.major,
+            device.device_bcd.minor,
+            device.device_bcd.patch,
+            device.usb_version.major,
+            device.usb_version.minor,
+            device.usb_version.patch,
+            device.device_class,
+            device.device_subclass,
+            device.device_protocol,
+            device.configuration_value(),
+            device.ep0_in.max_packet_size,
+            device.string_manufacturer,
+            device.string_product,
+            device.string_serial,
+        ));
+        let mut strings: Vec<_> = device.string_pool.iter().collect();
+        strings.sort();
+        for (index, s) in strings {
+            lines.push(format!(
+                "string index={} value={}",
+                index,
+                to_hex(s.as_bytes())
+            ));
+        }
+        for configuration in &device.configurations {
+            lines.push(format!(
+                "configuration value={} attributes={} max_power={} string={}",
+                configuration.configuration_value,
+                configuration.attributes,
+                configuration.max_power,
+                configuration.string_configuration
+            ));
+            for intf in &configuration.interfaces {
+                lines.push("interface".to_string());
+                for setting in &intf.alternate_settings {
+                    lines.push(format!(
+                        "setting class={} subclass={} protocol={} string={} class_specific={}",
+                        setting.interface_class,
+                        setting.interface_subclass,
+                        setting.interface_protocol,
+                        setting.string_interface,
+                        to_hex(&setting.class_specific_descriptor)
+                    ));
+                    for ep in &setting.endpoints {
+                        lines.push(format!(
+                            "endpoint address={} attributes={} max_packet_size={} interval={}",
+                            ep.address, ep.attributes, ep.max_packet_size, ep.interval
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut file = File::create(path)?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, urb: RecordedUrb) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{}", urb.to_line()) {
+            warn!("Failed to record URB: {}", err);
+        }
+    }
+
+    /// Record a URB unless the connection is going to fail
+    fn record_result(&self, urb: RecordedUrb, res: UrbResult<Vec<u8>>) -> UrbResult<Vec<u8>> {
+        match &res {
+            Ok(in_data) => self.record(RecordedUrb {
+                in_data: in_data.clone(),
+                ..urb
+            }),
+            Err(UrbError::Io(_)) => {}
+            Err(err) => self.record(RecordedUrb {
+                errno: err.errno().unwrap(),
+                ..urb
+            }),
+        }
+        res
+    }
+}
+
+/// Records the URBs of an interface, then passes them to the wrapped handler
+struct RecordingInterfaceHandler {
+    index: usize,
+    inner: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    recorder: Arc<Recorder>,
+}
+
+impl UsbInterfaceHandler for RecordingInterfaceHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.inner.lock().unwrap().get_class_specific_descriptor()
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        let res = self.inner.lock().unwrap().handle_urb(
+            interface,
+            ep,
+            transfer_buffer_length,
+            setup,
+            req,
+        );
+        let urb = RecordedUrb {
+            target: Target::Interface(self.index),
+            ep: ep.address,
+            setup: setup.to_bytes(),
+            iso_length: None,
+            out_data: req.to_vec(),
+            in_data: vec![],
+            errno: 0,
+        };
+        self.recorder.record_result(urb, res)
+    }
+
+    fn handle_iso_packet(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        length: u32,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        let res = self
+            .inner
+            .lock()
+            .unwrap()
+            .handle_iso_packet(interface, ep, length, req);
+        let urb = RecordedUrb {
+            target: Target::Interface(self.index),
+            ep: ep.address,
+            setup: [0; 8],
+            iso_length: Some(length),
+            out_data: req.to_vec(),
+            in_data: vec![],
+            errno: 0,
+        };
+        self.recorder.record_result(urb, res)
+    }
+
+    fn handle_zero_length_packet(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+    ) -> UrbResult<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .handle_zero_length_packet(interface, ep)
+    }
+
+    fn set_configured(&mut self, interface: &UsbInterface, configured: bool) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_configured(interface, configured)
+    }
+
+    fn set_alternate_setting(
+        &mut self,
+        interface: &UsbInterface,
+        number: u8,
+        alternate_setting: u8,
+    ) -> UrbResult<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_alternate_setting(interface, number, alternate_setting)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Records the URBs to a device, then passes them to the wrapped handler
+struct RecordingDeviceHandler {
+    inner: Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>,
+    recorder: Arc<Recorder>,
+}
+
+impl UsbDeviceHandler for RecordingDeviceHandler {
+    fn handle_urb(
+        &mut self,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        let res = self
+            .inner
+            .lock()
+            .unwrap()
+            .handle_urb(transfer_buffer_length, setup, req);
+        let urb = RecordedUrb {
+            target: Target::Device,
+            ep: 0,
+            setup: setup.to_bytes(),
+            iso_length: None,
+            out_data: req.to_vec(),
+            in_data: vec![],
+            errno: 0,
+        };
+        self.recorder.record_result(urb, res)
+    }
+
+    fn set_configuration(&mut self, configuration_value: u8) -> UrbResult<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_configuration(configuration_value)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDevice {
+    /// Record the descriptors of this device and every URB passed to its handlers to a file at `path`
+    ///
+    /// The handlers are wrapped, so [UsbInterfaceHandler::as_any] no longer returns the original handler.
+    /// Load the recording with [Recording::load] to replay it without the device.
+    pub fn with_recording(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let recorder = Arc::new(Recorder::create(path.as_ref(), &self)?);
+        // interfaces are numbered across configurations
+        let interfaces = self
+            .configurations
+            .iter_mut()
+            .flat_map(|configuration| &mut configuration.interfaces);
+        for (index, intf) in interfaces.enumerate() {
+            intf.handler = Arc::new(Mutex::new(Box::new(RecordingInterfaceHandler {
+                index,
+                inner: intf.handler.clone(),
+                recorder: recorder.clone(),
+            })));
+        }
+        if let Some(inner) = self.device_handler.take() {
+            self.device_handler = Some(Arc::new(Mutex::new(Box::new(RecordingDeviceHandler {
+                inner,
+                recorder,
+            }))));
+        }
+        Ok(self)
+    }
+}
+
+/// How replay handlers treat requests, see [Recording::into_device]
+///
+/// A request matches a recorded URB with the same endpoint, setup packet and OUT data.
+/// Matching URBs are answered in the recorded order, and the last one is repeated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplayStrictness {
+    /// Fail the connection on unmatched requests
+    Strict,
+    /// Stall unmatched requests
+    #[default]
+    Stall,
+    /// Ignore OUT data when matching, and stall unmatched requests
+    Lenient,
+}
+
+/// Recorded URBs of one handler
+struct ReplayUrbs {
+    urbs: Vec<RecordedUrb>,
+    /// Number of times each URB was replayed
+    replayed: Vec<usize>,
+    strictness: ReplayStrictness,
+}
+
+impl ReplayUrbs {
+    fn new(urbs: Vec<RecordedUrb>, strictness: ReplayStrictness) -> Self {
+        Self {
+            replayed: vec![0; urbs.len()],
+            urbs,
+            strictness,
+        }
+    }
+
+    fn replay(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        iso_length: Option<u32>,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        let matches: Vec<usize> = (0..self.urbs.len())
+            .filter(|&i| {
+                let urb = &self.urbs[i];
+                urb.ep == ep
+                    && urb.setup == setup
+                    && urb.iso_length == iso_length
+                    && (self.strictness == ReplayStrictness::Lenient || urb.out_data == req)
+            })
+            .collect();
+        // the first one not replayed yet, or the last one again
+        let Some(&index) = matches
+            .iter()
+            .find(|&&i| self.replayed[i] == 0)
+            .or(matches.last())
+        else {
+            warn!(
+                "No recorded URB for ep={:02x} setup={:02x?} req={:02x?}",
+                ep, setup, req
+            );
+            return match self.strictness {
+                ReplayStrictness::Strict => Err(UrbError::Io(std::io::Error::other(
+                    "URB not found in the recording",
+                ))),
+                _ => Err(UrbError::Stall),
+            };
+        };
+        self.replayed[index] += 1;
+        let urb = &self.urbs[index];
+        match UrbError::from_status(-urb.errno) {
+            Some(err) => Err(err),
+            None => Ok(urb.in_data.clone()),
+        }
+    }
+}
+
+/// Answers URBs to an interface from a recording
+pub struct ReplayInterfaceHandler {
+    class_specific_descriptor: Vec<u8>,
+    urbs: ReplayUrbs,
+}
+
+impl UsbInterfaceHandler for ReplayInterfaceHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.class_specific_descriptor.clone()
+    }
+
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        self.urbs.replay(ep.address, setup.to_bytes(), None, req)
+    }
+
+    fn handle_iso_packet(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        length: u32,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        self.urbs.replay(ep.address, [0; 8], Some(length), req)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Answers URBs to a device from a recording
+pub struct ReplayDeviceHandler {
+    urbs: ReplayUrbs,
+}
+
+impl UsbDeviceHandler for ReplayDeviceHandler {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> UrbResult<Vec<u8>> {
+        self.urbs.replay(0, setup.to_bytes(), None, req)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A recorded interface without handler
+struct RecordedInterface {
+    alternate_settings: Vec<UsbAlternateSetting>,
+}
+
+/// A recorded configuration without interface handlers
+struct RecordedConfiguration {
+    configuration_value: u8,
+    attributes: u8,
+    max_power: u8,
+    string_configuration: u8,
+    interfaces: Vec<RecordedInterface>,
+}
+
+/// A recording made by [UsbDevice::with_recording]
+pub struct Recording {
+    /// The device without configurations
+    device: UsbDevice,
+    configurations: Vec<RecordedConfiguration>,
+    urbs: Vec<RecordedUrb>,
+}
+
+impl Recording {
+    /// Read a recording from a file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut lines = content.lines();
+        lines
+            .next()
+            .and_then(|line| line.strip_prefix(RECORDING_HEADER)?.strip_prefix(' '))
+            .and_then(|version| version.parse::<u32>().ok())
+            .filter(|&version| version == RECORDING_VERSION)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "Not a recording"))?;
+        let mut recording = Recording {
+            device: UsbDevice::default(),
+            configurations: vec![],
+            urbs: vec![],
+        };
+        let mut num_interfaces = 0;
+        let device = &mut recording.device;
+        for line in lines.filter(|line| !line.is_empty()) {
+            let fields = Fields::parse(line);
+            let version = |key: &str| -> Result<device::Version> {
+                let mut parts = fields.str(key)?.split('.').map(str::parse::<u8>);
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+                        Ok(device::Version {
+                            major,
+                            minor,
+                            patch,
+                        })
+                    }
+                    _ => Err(invalid_data(line)),
+                }
+            };
+            let setting = || -> Result<UsbAlternateSetting> {
+                Ok(UsbAlternateSetting {
+                    interface_class: fields.num("class")?,
+                    interface_subclass: fields.num("subclass")?,
+                    interface_protocol: fields.num("protocol")?,
+                    endpoints: vec![],
+                    string_interface: fields.num("string")?,
+                    class_specific_descriptor: fields.hex("class_specific")?,
+                })
+            };
+            match line.split_whitespace().next() {
+                Some("device") => {
+                    let ep0_max_packet_size = fields.num("ep0_max_packet_size")?;
+                    *device = UsbDevice {
+                        speed: fields.num("speed")?,
+                        vendor_id: fields.num("vendor")?,
+                        product_id: fields.num("product")?,
+                        device_bcd: version("bcd")?,
+                        usb_version: version("usb")?,
+                        device_class: fields.num("class")?,
+                        device_subclass: fields.num("subclass")?,
+                        device_protocol: fields.num("protocol")?,
+                        active_configuration: Arc::new(Mutex::new(
+                            fields.num("active_configuration")?,
+                        )),
+                        ep0_in: UsbEndpoint {
+                            address: 0x80,
+                            attributes: EndpointAttributes::Control as u8,
+                            max_packet_size: ep0_max_packet_size,
+                            interval: 0,
+                        },
+                        ep0_out: UsbEndpoint {
+                            address: 0x00,
+                            attributes: EndpointAttributes::Control as u8,
+                            max_packet_size: ep0_max_packet_size,
+                            interval: 0,
+                        },
+                        string_manufacturer: fields.num("string_manufacturer")?,
+                        string_product: fields.num("string_product")?,
+                        string_serial: fields.num("string_serial")?,
+                        ..UsbDevice::default()
+                    };
+                }
+                Some("string") => {
+                    let value =
+                        String::from_utf8(fields.hex("value")?).map_err(|_| invalid_data(line))?;
+                    device.string_pool.insert(fields.num("index")?, value);
+                }
+                Some("configuration") => {
+                    recording.configurations.push(RecordedConfiguration {
+                        configuration_value: fields.num("value")?,
+                        attributes: fields.num("attributes")?,
+                        max_power: fields.num("max_power")?,
+                        string_configuration: fields.num("string")?,
+                        interfaces: vec![],
+                    });
+                }
+                Some("interface") => {
+                    let configuration = recording
+                        .configurations
+                        .last_mut()
+                        .ok_or_else(|| invalid_data(line))?;
+                    num_interfaces += 1;
+                    // followed by its setting lines
+                    configuration.interfaces.push(RecordedInterface {
+                        alternate_settings: vec![],
+                    });
+                }
+                Some("setting") => {
+                    let intf = recording
+                        .configurations
+                        .last_mut()
+                        .and_then(|configuration| configuration.interfaces.last_mut())
+                        .ok_or_else(|| invalid_data(line))?;
+                    intf.alternate_settings.push(setting()?);
+                }
+                Some("endpoint") => {
+                    let setting = recording
+                        .configurations
+                        .last_mut()
+                        .and_then(|configuration| configuration.interfaces.last_mut())
+                        .and_then(|intf| intf.alternate_settings.last_mut())
+                        .ok_or_else(|| invalid_data(line))?;
+                    setting.endpoints.push(UsbEndpoint {
+                        address: fields.num("address")?,
+                        attributes: fields.num("attributes")?,
+                        max_packet_size: fields.num("max_packet_size")?,
+                        interval: fields.num("interval")?,
+                    });
+                }
+                Some("urb") => {
+                    let urb = RecordedUrb::parse(&fields)?;
+                    if let Target::Interface(index) = urb.target {
+                        if index >= num_interfaces {
+                            return Err(invalid_data(line));
+                        }
+                    }
+                    recording.urbs.push(urb);
+                }
+                _ => return Err(invalid_data(line)),
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Build a simulated device answering requests from this recording
+    ///
+    /// Descriptors are answered from the recorded device, other requests by [ReplayInterfaceHandler] and [ReplayDeviceHandler].
+    pub fn into_device(self, index: u32, strictness: ReplayStrictness) -> UsbDevice {
+        let urbs_of = |target: Target| -> Vec<RecordedUrb> {
+            self.urbs
+                .iter()
+                .filter(|urb| urb.target == target)
+                .cloned()
+                .collect()
+        };
+        // interfaces are numbered across configurations
+        let mut interface_index = 0;
+        let mut interface = |intf: RecordedInterface| {
+            let handler = ReplayInterfaceHandler {
+                class_specific_descriptor: intf
+                    .alternate_settings
+                    .first()
+                    .map(|setting| setting.class_specific_descriptor.clone())
+                    .unwrap_or_default(),
+                urbs: ReplayUrbs::new(urbs_of(Target::Interface(interface_index)), strictness),
+            };
+            interface_index += 1;
+            UsbInterface::new(
+                intf.alternate_settings,
+                Arc::new(Mutex::new(Box::new(handler))),
+            )
+        };
+        let configurations = self
+            .configurations
+            .into_iter()
+            .map(|configuration| UsbConfiguration {
+                configuration_value: configuration.configuration_value,
+                attributes: configuration.attributes,
+                max_power: configuration.max_power,
+                string_configuration: configuration.string_configuration,
+                interfaces: configuration
+                    .interfaces
+                    .into_iter()
+                    .map(&mut interface)
+                    .collect(),
+            })
+            .collect();
+        UsbDevice {
+            path: format!("/sys/device/usbip/{}", index),
+            bus_id: format!("{}", index),
+            dev_num: index,
+            device_handler: Some(Arc::new(Mutex::new(Box::new(ReplayDeviceHandler {
+                urbs: ReplayUrbs::new(urbs_of(Target::Device), strictness),
+            })))),
+            configurations,
+            ..self.device
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts bulk OUT bytes and answers bulk IN with the count
+    struct CountingHandler {
+        count: u8,
+    }
+
+    impl UsbInterfaceHandler for CountingHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![0x04, 0x24, 0x02, 0x00]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            setup: SetupPacket,
+            req: &[u8],
+        ) -> UrbResult<Vec<u8>> {
+            match ep.address {
+                0x02 => {
+                    self.count += req.len() as u8;
+                    Ok(vec![])
+                }
+                0x82 => Ok(vec![self.count]),
+                // class request to interface
+                _ if setup.request == 0x21 => Ok(vec![0x80, 0x25, 0x00, 0x00]),
+                _ => Err(UrbError::Stall),
+            }
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    async fn serve(
+        device: UsbDevice,
+    ) -> (UsbIpServerHandle, UsbIpClientDevice<tokio::net::TcpStream>) {
+        let handle = UsbIpServer::new_simulated(vec![device])
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UsbIpClient::connect(handle.local_addr())
+            .await
+            .unwrap()
+            .import("0")
+            .await
+            .unwrap();
+        (handle, client)
+    }
+
+    // GET_LINE_CODING to interface 0
+    const GET_LINE_CODING: SetupPacket = SetupPacket {
+        request_type: 0xa1,
+        request: 0x21,
+        value: 0,
+        index: 0,
+        length: 7,
+    };
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("usbip-test-{}.recording", std::process::id()));
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Recorded interface",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(Mutex::new(Box::new(CountingHandler { count: 0 }))),
+        );
+        device.vendor_id = 0x1234;
+        let (handle, client) = serve(device.with_recording(&path).unwrap()).await;
+        client.bulk_out(0x02, b"abc").await.unwrap();
+        assert_eq!(client.bulk_in(0x82, 64).await.unwrap(), vec![3]);
+        client.bulk_out(0x02, b"de").await.unwrap();
+        assert_eq!(client.bulk_in(0x82, 64).await.unwrap(), vec![5]);
+        assert_eq!(
+            client.control_in(GET_LINE_CODING).await.unwrap(),
+            vec![0x80, 0x25, 0x00, 0x00]
+        );
+        handle.shutdown().await;
+
+        let recording = Recording::load(&path).unwrap();
+        let (handle, client) = serve(recording.into_device(0, ReplayStrictness::Stall)).await;
+        let info = client.info();
+        assert_eq!(info.vendor_id, 0x1234);
+        // descriptors are answered by the rebuilt device
+        let desc = client
+            .control_in(SetupPacket {
+                request_type: 0x80,
+                request: StandardRequest::GetDescriptor as u8,
+                value: (DescriptorType::Configuration as u16) << 8,
+                index: 0,
+                length: 0xff,
+            })
+            .await
+            .unwrap();
+        assert_eq!(&desc[18..22], &[0x04, 0x24, 0x02, 0x00]);
+        // recorded responses in order, the last one repeated
+        client.bulk_out(0x02, b"abc").await.unwrap();
+        assert_eq!(client.bulk_in(0x82, 64).await.unwrap(), vec![3]);
+        assert_eq!(client.bulk_in(0x82, 64).await.unwrap(), vec![5]);
+        assert_eq!(client.bulk_in(0x82, 64).await.unwrap(), vec![5]);
+        assert_eq!(
+            client.control_in(GET_LINE_CODING).await.unwrap(),
+            vec![0x80, 0x25, 0x00, 0x00]
+        );
+        // OUT data not in the recording
+        assert!(matches!(
+            client.bulk_out(0x02, b"xyz").await,
+            Err(UrbError::Stall)
+        ));
+        handle.shutdown().await;
+
+        let recording = Recording::load(&path).unwrap();
+        let (handle, client) = serve(recording.into_device(0, ReplayStrictness::Lenient)).await;
+        client.bulk_out(0x02, b"xyz").await.unwrap();
+        handle.shutdown().await;
+
+        let recording = Recording::load(&path).unwrap();
+        let (handle, client) = serve(recording.into_device(0, ReplayStrictness::Strict)).await;
+        assert!(matches!(
+            client.bulk_out(0x02, b"xyz").await,
+            Err(UrbError::Io(_))
+        ));
+        handle.shutdown().await;
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn invalid_recording() {
+        let header = format!("{} {}", RECORDING_HEADER, RECORDING_VERSION);
+        assert!(Recording::parse("").is_err());
+        assert!(Recording::parse(&header).is_ok());
+        assert!(Recording::parse(&format!("{} 0", RECORDING_HEADER)).is_err());
+        assert!(
+            Recording::parse(&format!("{} {}", RECORDING_HEADER, RECORDING_VERSION + 1)).is_err()
+        );
+        assert!(Recording::parse(&format!("{}\nurb target=0 ep=2", header)).is_err());
+        assert!(Recording::parse(&format!(
+            "{}\nurb target=device ep=0 setup=00 out= in= errno=0",
+            header
+        ))
+        .is_err());
+        // the interface of a URB is missing
+        assert!(Recording::parse(&format!(
+            "{}\nurb target=0 ep=130 setup=0000000000000000 out= in=03 errno=0",
+            header
+        ))
+        .is_err());
+        // settings have their own lines
+        assert!(Recording::parse(&format!(
+            "{}\nconfiguration value=1 attributes=128 max_power=50 string=0\ninterface class=2 subclass=2 protocol=0 string=0 class_specific=\nendpoint address=130 attributes=2 max_packet_size=512 interval=0",
+            header
+        ))
+        .is_err());
+    }
+}
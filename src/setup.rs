@@ -1,3 +1,5 @@
+use super::*;
+
 /// Parse the SETUP packet of control transfers
 #[derive(Clone, Copy, Debug, Default)]
 pub struct SetupPacket {
@@ -24,4 +26,27 @@ impl SetupPacket {
             length: (setup[7] as u16) << 8 | (setup[6] as u16),
         }
     }
+
+    /// Direction of the data stage from bmRequestType, [Direction::Out] if wLength is zero
+    pub fn direction(&self) -> Direction {
+        if self.request_type & 0x80 != 0 && self.length > 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    /// Encode this [SetupPacket] into a raw setup packet
+    pub fn to_bytes(&self) -> [u8; 8] {
+        [
+            self.request_type,
+            self.request,
+            self.value as u8,
+            (self.value >> 8) as u8,
+            self.index as u8,
+            (self.index >> 8) as u8,
+            self.length as u8,
+            (self.length >> 8) as u8,
+        ]
+    }
 }
@@ -0,0 +1,230 @@
+//! TLS transport
+use super::*;
+use tokio::task::JoinSet;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+pub use tokio_rustls::rustls;
+
+/// Default time a client has to complete the TLS handshake
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default max number of TLS handshakes in progress
+pub const DEFAULT_MAX_TLS_HANDSHAKES: usize = 64;
+
+/// A listener wrapping connections of `L` in TLS
+///
+/// To require client certificates, build `config` with a client verifier such as
+/// [rustls::server::WebPkiClientVerifier]. The verified certificate is available in
+/// [ConnectionInfo::client_certificate].
+///
+/// Handshakes happen before the connection reaches the server, so they have their own
+/// timeout and cap, see [TlsListener::with_handshake_timeout] and [TlsListener::with_max_handshakes].
+pub struct TlsListener<L: UsbIpListener> {
+    inner: L,
+    acceptor: TlsAcceptor,
+    /// Handshakes run concurrently, so a slow client does not block others
+    handshakes: JoinSet<Result<(TlsStream<L::Stream>, ConnectionInfo)>>,
+    handshake_timeout: Duration,
+    max_handshakes: usize,
+}
+
+impl<L: UsbIpListener> TlsListener<L> {
+    pub fn new(inner: L, config: Arc<rustls::ServerConfig>) -> Self {
+        Self {
+            inner,
+            acceptor: TlsAcceptor::from(config),
+            handshakes: JoinSet::new(),
+            handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+            max_handshakes: DEFAULT_MAX_TLS_HANDSHAKES,
+        }
+    }
+
+    /// Close connections that have not completed the TLS handshake within `timeout`
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Stop accepting connections while `max` TLS handshakes are in progress, at least one
+    pub fn with_max_handshakes(mut self, max: usize) -> Self {
+        self.max_handshakes = max.max(1);
+        self
+    }
+}
+
+impl<L: UsbIpListener> UsbIpListener for TlsListener<L> {
+    type Stream = TlsStream<L::Stream>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> Result<(Self::Stream, ConnectionInfo)> {
+        loop {
+            // further clients wait in the backlog of the inner listener
+            let accepting = self.handshakes.len() < self.max_handshakes;
+            tokio::select! {
+                res = self.inner.accept(), if accepting => {
+                    let (stream, mut info) = res?;
+                    let acceptor = self.acceptor.clone();
+                    let timeout = self.handshake_timeout;
+                    self.handshakes.spawn(async move {
+                        let stream = tokio::time::timeout(timeout, acceptor.accept(stream))
+                            .await
+                            .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+                        info.client_certificate = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .map(|cert| cert.to_vec());
+                        Ok((stream, info))
+                    });
+                }
+                Some(res) = self.handshakes.join_next() => match res {
+                    Ok(Ok(res)) => return Ok(res),
+                    Ok(Err(err)) => warn!("TLS handshake failed: {}", err),
+                    Err(err) => warn!("TLS handshake aborted: {}", err),
+                },
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    struct TestPki {
+        ca: CertificateDer<'static>,
+        server: (CertificateDer<'static>, PrivateKeyDer<'static>),
+        client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+    }
+
+    fn test_pki() -> TestPki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let issue = |name: &str| {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            (
+                cert.der().clone(),
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )
+        };
+        let server = issue("localhost");
+        let client = issue("client");
+        TestPki {
+            ca: ca.der().clone(),
+            server,
+            client,
+        }
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        pki: &TestPki,
+        with_client_cert: bool,
+    ) -> UsbIpClient<tokio_rustls::client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).unwrap();
+        let config = ClientConfig::builder().with_root_certificates(roots);
+        let config = if with_client_cert {
+            config
+                .with_client_auth_cert(vec![pki.client.0.clone()], pki.client.1.clone_key())
+                .unwrap()
+        } else {
+            config.with_no_client_auth()
+        };
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+            .unwrap();
+        UsbIpClient::new(stream)
+    }
+
+    #[tokio::test]
+    async fn select_devices_by_client_certificate() {
+        let pki = test_pki();
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).unwrap();
+        // client certificates are optional
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .allow_unauthenticated()
+            .build()
+            .unwrap();
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![pki.server.0.clone()], pki.server.1.clone_key())
+            .unwrap();
+
+        let client_cert = pki.client.0.to_vec();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_access_policy(
+            move |info: &ConnectionInfo, _device: &UsbDevice| {
+                info.client_certificate.as_ref() == Some(&client_cert)
+            },
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = server
+            .serve_listener(TlsListener::new(listener, Arc::new(config)))
+            .unwrap();
+        let addr = handle.local_addr();
+
+        let mut anonymous = connect(addr, &pki, false).await;
+        assert!(anonymous.list_devices().await.unwrap().is_empty());
+        assert!(connect(addr, &pki, false).await.import("0").await.is_err());
+
+        let mut authenticated = connect(addr, &pki, true).await;
+        assert_eq!(authenticated.list_devices().await.unwrap().len(), 1);
+        assert!(connect(addr, &pki, true).await.import("0").await.is_ok());
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn silent_client_times_out() {
+        let pki = test_pki();
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![pki.server.0.clone()], pki.server.1.clone_key())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, Arc::new(config))
+            .with_handshake_timeout(Duration::from_millis(200))
+            .with_max_handshakes(1);
+        let handle = UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+            .serve_listener(listener)
+            .unwrap();
+        let addr = handle.local_addr();
+
+        // never sends a ClientHello, and is accepted first from the backlog
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+
+        // waits until the silent handshake times out
+        let started = std::time::Instant::now();
+        let mut client = connect(addr, &pki, false).await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(client.list_devices().await.unwrap().len(), 1);
+
+        let mut buf = [0u8; 1];
+        assert!(matches!(silent.read(&mut buf).await, Ok(0) | Err(_)));
+        handle.shutdown().await;
+    }
+}
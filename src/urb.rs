@@ -0,0 +1,115 @@
+//! Completion status of URBs
+
+// errno values of Linux, negated in the status field of USBIP_RET_SUBMIT and USBIP_RET_UNLINK
+// see https://www.kernel.org/doc/html/latest/driver-api/usb/error-codes.html
+pub(crate) const ENOMEM: i32 = 12;
+pub(crate) const ENODEV: i32 = 19;
+pub(crate) const EPIPE: i32 = 32;
+pub(crate) const EPROTO: i32 = 71;
+pub(crate) const EOVERFLOW: i32 = 75;
+pub(crate) const EMSGSIZE: i32 = 90;
+pub(crate) const ECONNRESET: i32 = 104;
+pub(crate) const ETIMEDOUT: i32 = 110;
+pub(crate) const EINPROGRESS: i32 = 115;
+pub(crate) const EREMOTEIO: i32 = 121;
+
+/// Max number of packets in an isochronous URB, as in Linux
+pub(crate) const USBIP_MAX_ISO_PACKETS: u32 = 1024;
+
+/// Result of handling a URB
+pub type UrbResult<T> = std::result::Result<T, UrbError>;
+
+/// An error completing a URB
+///
+/// All variants except [UrbError::Io] are reported to the client in the status field of USBIP_RET_SUBMIT
+#[derive(Debug)]
+pub enum UrbError {
+    /// Endpoint stalled, e.g. an unsupported request (-EPIPE)
+    Stall,
+    /// No response in time (-ETIMEDOUT)
+    Timeout,
+    /// Device sent more data than requested (-EOVERFLOW)
+    Overflow,
+    /// Device has been removed (-ENODEV)
+    NoDevice,
+    /// Short packet while URB_SHORT_NOT_OK is set (-EREMOTEIO)
+    ShortNotOk,
+    /// Protocol error on the bus (-EPROTO)
+    Protocol,
+    /// Local error, the connection is closed
+    Io(std::io::Error),
+}
+
+impl UrbError {
+    /// Linux errno of this error, `None` for [UrbError::Io]
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            UrbError::Stall => Some(EPIPE),
+            UrbError::Timeout => Some(ETIMEDOUT),
+            UrbError::Overflow => Some(EOVERFLOW),
+            UrbError::NoDevice => Some(ENODEV),
+            UrbError::ShortNotOk => Some(EREMOTEIO),
+            UrbError::Protocol => Some(EPROTO),
+            UrbError::Io(_) => None,
+        }
+    }
+
+    /// Error of a non-zero status in USBIP_RET_SUBMIT
+    ///
+    /// Unknown statuses are reported as [UrbError::Protocol]
+    pub fn from_status(status: i32) -> Option<UrbError> {
+        match status.wrapping_neg() {
+            0 => None,
+            EPIPE => Some(UrbError::Stall),
+            ETIMEDOUT => Some(UrbError::Timeout),
+            EOVERFLOW => Some(UrbError::Overflow),
+            ENODEV => Some(UrbError::NoDevice),
+            EREMOTEIO => Some(UrbError::ShortNotOk),
+            _ => Some(UrbError::Protocol),
+        }
+    }
+}
+
+impl From<std::io::Error> for UrbError {
+    fn from(err: std::io::Error) -> Self {
+        UrbError::Io(err)
+    }
+}
+
+impl From<rusb::Error> for UrbError {
+    fn from(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::Pipe => UrbError::Stall,
+            rusb::Error::Timeout => UrbError::Timeout,
+            rusb::Error::Overflow => UrbError::Overflow,
+            rusb::Error::NoDevice => UrbError::NoDevice,
+            _ => UrbError::Protocol,
+        }
+    }
+}
+
+impl std::fmt::Display for UrbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UrbError::Io(err) => write!(f, "{}", err),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::error::Error for UrbError {}
+
+/// Descriptor of a packet in an isochronous URB
+///
+/// Follows the transfer buffer in USBIP_CMD_SUBMIT and USBIP_RET_SUBMIT
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IsoPacketDescriptor {
+    /// Offset of the packet in the transfer buffer
+    pub offset: u32,
+    /// Requested length
+    pub length: u32,
+    /// Actual length of transferred data
+    pub actual_length: u32,
+    /// Completion status: zero or a negative Linux errno
+    pub status: i32,
+}
@@ -1,16 +1,27 @@
 use super::*;
 
-pub(crate) async fn socket_write_fixed_string<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+pub(crate) async fn socket_write_fixed_string<T: AsyncWriteExt + Unpin>(
     socket: &mut T,
-    s: &String,
+    s: &str,
     len: usize,
 ) -> Result<()> {
-    let mut path = s.clone().into_bytes();
+    let mut path = s.as_bytes().to_vec();
     assert!(path.len() <= len);
     path.resize(len, 0);
     socket.write_all(&path).await
 }
 
+pub(crate) async fn socket_read_fixed_string<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    len: usize,
+) -> Result<String> {
+    let mut buf = vec![0u8; len];
+    socket.read_exact(&mut buf).await?;
+    // strip trailing NULs
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&buf[..end]).into_owned())
+}
+
 /// Check validity of a USB descriptor
 pub fn verify_descriptor(desc: &[u8]) {
     let mut offset = 0;
@@ -22,13 +33,63 @@ pub fn verify_descriptor(desc: &[u8]) {
 
 #[cfg(test)]
 pub(crate) mod tests {
+    use crate::{cdc, ClassCode, UsbDevice, UsbInterfaceHandler, UsbIpServer};
     use std::{
         io::*,
         pin::Pin,
+        sync::{Arc, Mutex},
         task::{Context, Poll},
     };
     use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+    /// OP_REQ_IMPORT of the device at `bus_id`
+    pub(crate) async fn import_request(bus_id: &str) -> Vec<u8> {
+        let mut req = vec![];
+        crate::OpReqImport {
+            bus_id: bus_id.to_string(),
+            ..Default::default()
+        }
+        .write(&mut req)
+        .await
+        .unwrap();
+        req
+    }
+
+    /// Device 0 with a simulated CDC ACM interface
+    pub(crate) fn cdc_device() -> UsbDevice {
+        let handler = Arc::new(Mutex::new(
+            Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            handler,
+        )
+    }
+
+    /// Server simulating [cdc_device]
+    pub(crate) fn cdc_server() -> UsbIpServer {
+        UsbIpServer::new_simulated(vec![cdc_device()])
+    }
+
+    /// Seqnum and status of the first `count` USBIP_RET_SUBMIT in `output`, which carry no data
+    /// except the last one
+    pub(crate) fn parse_ret_submits(output: &[u8], count: usize) -> Vec<(u32, i32)> {
+        output
+            .chunks(0x30)
+            .take(count)
+            .map(|ret| {
+                (
+                    u32::from_be_bytes(ret[4..8].try_into().unwrap()),
+                    i32::from_be_bytes(ret[20..24].try_into().unwrap()),
+                )
+            })
+            .collect()
+    }
+
     pub(crate) struct MockSocket {
         pub input: Cursor<Vec<u8>>,
         pub output: Vec<u8>,